@@ -0,0 +1,531 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    env,
+    fmt::{self, Display},
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{Context as _, Result};
+use serde::Serialize;
+
+use crate::git::{FETCH_MARKER, URL_FILE};
+use crate::registry::INDEX_DIR;
+
+/// Environment variable overriding the cache directory, unless `--cache-dir` is given
+pub const CACHE_DIR_ENV: &str = "CARGO_GOGGLES_CACHE_DIR";
+
+/// Marks the directory as a cache, so that backup tools can skip it
+///
+/// See <https://bford.info/cachedir/>.
+const CACHEDIR_TAG: &str = "Signature: 8a477f597d28d172789f06886806bc55
+# This file is a cache directory tag created by cargo-goggles.
+# For information about cache directory tags see https://bford.info/cachedir/
+";
+
+/// The per-user directory holding downloaded crates and cloned repositories
+///
+/// Its contents are trusted, so it must only be writable by the current user.
+#[derive(Debug)]
+pub struct Cache {
+    dir: PathBuf,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CacheEntryKind {
+    /// A downloaded `.crate` file
+    Crate,
+    /// A shallow clone of the index of a git registry
+    RegistryIndex,
+    /// A bare clone of a repository
+    Repository,
+    /// A checkout or a partial clone left behind by an interrupted run
+    Leftover,
+}
+
+/// Something kept in the cache
+#[derive(Debug, Serialize)]
+pub struct CacheEntry {
+    pub kind: CacheEntryKind,
+    /// `<name>@<version>` for crates, the url of repositories
+    pub name: String,
+    pub path: PathBuf,
+    /// Size on disk, in bytes
+    pub size: u64,
+    /// When the entry was last used, in seconds since the Unix epoch
+    pub last_used: u64,
+}
+
+/// How much space the cache takes, in bytes
+#[derive(Debug, Serialize)]
+pub struct CacheSize {
+    pub dir: PathBuf,
+    pub total: u64,
+    pub kinds: BTreeMap<CacheEntryKind, KindSize>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct KindSize {
+    pub entries: usize,
+    pub size: u64,
+}
+
+/// The entries needed to analyze a `Cargo.lock`
+#[derive(Debug, Default)]
+pub struct CacheUsage {
+    /// Crates as `<name>@<version>`
+    pub crates: BTreeSet<String>,
+    /// Names of the clones in the repositories directory
+    pub repositories: BTreeSet<String>,
+}
+
+impl Cache {
+    /// Open the cache in `dir`, [`CACHE_DIR_ENV`] or the user's cache directory
+    ///
+    /// The directory is created if needed and is refused if it's owned by
+    /// another user. Permissions granting access to other users are revoked.
+    pub fn open(dir: Option<PathBuf>) -> Result<Self> {
+        let dir = match dir {
+            Some(dir) => dir,
+            None => default_dir().context("find the cache directory")?,
+        };
+
+        create_private_dir(&dir)
+            .with_context(|| format!("create cache directory {}", dir.display()))?;
+        let cache = Self { dir };
+        for dir in [cache.crates_dir(), cache.repositories_dir()] {
+            create_private_dir(&dir)
+                .with_context(|| format!("create cache directory {}", dir.display()))?;
+        }
+
+        let tag = cache.dir.join("CACHEDIR.TAG");
+        if !tag.try_exists()? {
+            fs::write(&tag, CACHEDIR_TAG).context("write `CACHEDIR.TAG`")?;
+        }
+
+        Ok(cache)
+    }
+
+    /// Where `.crate` files are downloaded, one directory per registry
+    pub fn crates_dir(&self) -> PathBuf {
+        self.dir.join("crates")
+    }
+
+    /// Where repositories are cloned
+    pub fn repositories_dir(&self) -> PathBuf {
+        self.dir.join("repositories")
+    }
+
+    /// List downloaded crates, registry indexes, clones and leftovers of
+    /// interrupted runs
+    ///
+    /// Checkouts and clones in progress in a running process are skipped.
+    pub fn entries(&self) -> Result<Vec<CacheEntry>> {
+        let mut entries = Vec::new();
+
+        //
+        // Crates and registry indexes, as `<registry>/<name>/<version>.tar.gz`
+        //
+
+        for registry in read_dirs(&self.crates_dir())? {
+            for dir in read_dirs(&registry)? {
+                let dir_name = file_name(&dir);
+                if dir_name.ends_with(".tmp") {
+                    // Scratch clones of registry indexes
+                    if is_abandoned_clone(&dir_name) {
+                        entries.push(CacheEntry::new(
+                            CacheEntryKind::Leftover,
+                            format!("{}/{dir_name}", file_name(&registry)),
+                            dir,
+                            None,
+                        )?);
+                    }
+                    continue;
+                }
+                if dir_name == INDEX_DIR {
+                    entries.push(CacheEntry::new(
+                        CacheEntryKind::RegistryIndex,
+                        file_name(&registry),
+                        dir,
+                        None,
+                    )?);
+                    continue;
+                }
+
+                for entry in fs::read_dir(&dir)? {
+                    let path = entry?.path();
+                    // Partial downloads are overwritten by the next download
+                    let Some(version) = file_name(&path)
+                        .strip_suffix(".tar.gz")
+                        .map(|version| version.trim_end_matches(".vendored").to_owned())
+                    else {
+                        continue;
+                    };
+                    entries.push(CacheEntry::new(
+                        CacheEntryKind::Crate,
+                        format!("{dir_name}@{version}"),
+                        path,
+                        None,
+                    )?);
+                }
+            }
+        }
+
+        //
+        // Clones, along with the scratch directories of checkouts and clones
+        //
+
+        for dir in read_dirs(&self.repositories_dir())? {
+            let name = file_name(&dir);
+            if name.ends_with(".checkouts") {
+                // Named `<commit>.<pid>-<counter>`
+                for checkout in read_dirs(&dir)? {
+                    let checkout_name = file_name(&checkout);
+                    if scratch_pid(checkout_name.split_once('.').map(|(_, suffix)| suffix))
+                        .is_none_or(is_running)
+                    {
+                        continue;
+                    }
+                    entries.push(CacheEntry::new(
+                        CacheEntryKind::Leftover,
+                        format!("{name}/{checkout_name}"),
+                        checkout,
+                        None,
+                    )?);
+                }
+            } else if name.ends_with(".tmp") {
+                if is_abandoned_clone(&name) {
+                    entries.push(CacheEntry::new(CacheEntryKind::Leftover, name, dir, None)?);
+                }
+            } else {
+                let url = fs::read_to_string(dir.join(URL_FILE)).unwrap_or(name);
+                let last_used = [URL_FILE, FETCH_MARKER]
+                    .into_iter()
+                    .filter_map(|file| last_used(&dir.join(file)).ok())
+                    .max();
+                entries.push(CacheEntry::new(
+                    CacheEntryKind::Repository,
+                    url,
+                    dir,
+                    last_used,
+                )?);
+            }
+        }
+
+        entries.sort_by(|a, b| (a.kind, &a.name).cmp(&(b.kind, &b.name)));
+        Ok(entries)
+    }
+
+    /// Remove leftovers of interrupted runs, and the entries which are both
+    /// older than `older_than` and not in `used`
+    ///
+    /// Returns the removed entries.
+    pub fn prune(
+        &self,
+        older_than: Option<Duration>,
+        used: Option<&CacheUsage>,
+    ) -> Result<Vec<CacheEntry>> {
+        let now = unix_time(SystemTime::now());
+
+        let mut removed = Vec::new();
+        for entry in self.entries()? {
+            let prune = entry.kind == CacheEntryKind::Leftover
+                || (older_than.is_none_or(|older_than| {
+                    now.saturating_sub(entry.last_used) >= older_than.as_secs()
+                }) && used.is_none_or(|used| !used.uses(&entry)));
+            if prune {
+                remove(&entry.path).with_context(|| format!("remove {}", entry.path.display()))?;
+                removed.push(entry);
+            }
+        }
+
+        // Drop the directories of checkouts which are now empty
+        for dir in read_dirs(&self.repositories_dir())? {
+            if file_name(&dir).ends_with(".checkouts") {
+                let _ = fs::remove_dir(&dir);
+            }
+        }
+
+        Ok(removed)
+    }
+
+    /// Remove every crate and repository, returning the freed space in bytes
+    pub fn clean(&self) -> Result<u64> {
+        let mut freed = 0;
+        for dir in [self.crates_dir(), self.repositories_dir()] {
+            for entry in fs::read_dir(&dir)? {
+                let path = entry?.path();
+                freed += size(&path)?;
+                remove(&path).with_context(|| format!("remove {}", path.display()))?;
+            }
+        }
+
+        Ok(freed)
+    }
+
+    /// Sum the size of `entries` by kind, along with the size of the whole cache
+    pub fn size(&self, entries: &[CacheEntry]) -> Result<CacheSize> {
+        let mut kinds = BTreeMap::<_, KindSize>::new();
+        for entry in entries {
+            let kind_size = kinds.entry(entry.kind).or_default();
+            kind_size.entries += 1;
+            kind_size.size += entry.size;
+        }
+
+        Ok(CacheSize {
+            dir: self.dir.clone(),
+            total: size(&self.dir)?,
+            kinds,
+        })
+    }
+}
+
+impl CacheEntry {
+    /// `last_used` defaults to the modification time of `path`
+    fn new(
+        kind: CacheEntryKind,
+        name: String,
+        path: PathBuf,
+        last_used: Option<u64>,
+    ) -> Result<Self> {
+        let last_used = match last_used {
+            Some(last_used) => last_used,
+            None => self::last_used(&path)?,
+        };
+        Ok(Self {
+            kind,
+            name,
+            size: size(&path)?,
+            path,
+            last_used,
+        })
+    }
+}
+
+impl CacheSize {
+    pub fn write_human(&self, mut writer: impl Write) -> io::Result<()> {
+        for (kind, KindSize { entries, size }) in &self.kinds {
+            writeln!(writer, "{:>10}  {entries} {kind}", format_size(*size))?;
+        }
+        writeln!(
+            writer,
+            "{:>10}  total in {}",
+            format_size(self.total),
+            self.dir.display()
+        )
+    }
+}
+
+impl CacheUsage {
+    /// Whether the entry is needed, registry indexes always are
+    fn uses(&self, entry: &CacheEntry) -> bool {
+        match entry.kind {
+            CacheEntryKind::Crate => self.crates.contains(&entry.name),
+            CacheEntryKind::Repository => self.repositories.contains(&file_name(&entry.path)),
+            CacheEntryKind::RegistryIndex => true,
+            CacheEntryKind::Leftover => false,
+        }
+    }
+}
+
+impl Display for CacheEntryKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Crate => "crate",
+            Self::RegistryIndex => "registry-index",
+            Self::Repository => "repository",
+            Self::Leftover => "leftover",
+        })
+    }
+}
+
+/// Write one line per entry, with its size and how long ago it was last used
+pub fn write_list(entries: &[CacheEntry], mut writer: impl Write) -> io::Result<()> {
+    let now = unix_time(SystemTime::now());
+    for entry in entries {
+        writeln!(
+            writer,
+            "{:>10}  {:>4}  {:14}  {}",
+            format_size(entry.size),
+            format_age(now.saturating_sub(entry.last_used)),
+            entry.kind.to_string(),
+            entry.name
+        )?;
+    }
+
+    Ok(())
+}
+
+/// The directories inside `dir`, none if it doesn't exist
+fn read_dirs(dir: &Path) -> Result<Vec<PathBuf>> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err).with_context(|| format!("read {}", dir.display())),
+    };
+
+    let mut dirs = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            dirs.push(entry.path());
+        }
+    }
+    dirs.sort_unstable();
+
+    Ok(dirs)
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// The total size of the files in `path`, without following symlinks
+fn size(path: &Path) -> io::Result<u64> {
+    let metadata = fs::symlink_metadata(path)?;
+    if !metadata.is_dir() {
+        return Ok(metadata.len());
+    }
+
+    let mut size = 0;
+    for entry in fs::read_dir(path)? {
+        size += self::size(&entry?.path())?;
+    }
+    Ok(size)
+}
+
+fn remove(path: &Path) -> io::Result<()> {
+    if fs::symlink_metadata(path)?.is_dir() {
+        fs::remove_dir_all(path)
+    } else {
+        fs::remove_file(path)
+    }
+}
+
+fn last_used(path: &Path) -> io::Result<u64> {
+    Ok(unix_time(fs::metadata(path)?.modified()?))
+}
+
+fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// The process id in a `<pid>-<counter>` scratch directory suffix
+fn scratch_pid(suffix: Option<&str>) -> Option<u32> {
+    suffix?.split_once('-')?.0.parse().ok()
+}
+
+/// Whether `name` is a scratch clone, named `<name>.<pid>-<counter>.tmp`,
+/// whose process is gone
+fn is_abandoned_clone(name: &str) -> bool {
+    let suffix = name
+        .strip_suffix(".tmp")
+        .and_then(|clone_name| clone_name.rsplit_once('.'))
+        .map(|(_, suffix)| suffix);
+    scratch_pid(suffix).is_some_and(|pid| !is_running(pid))
+}
+
+/// Whether `pid` may still be using its scratch directories
+#[cfg(unix)]
+fn is_running(pid: u32) -> bool {
+    let Ok(pid) = libc::pid_t::try_from(pid) else {
+        return true;
+    };
+    // SAFETY: signal 0 only checks whether the process exists
+    let exists = unsafe { libc::kill(pid, 0) } == 0;
+    exists || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(not(unix))]
+fn is_running(_pid: u32) -> bool {
+    true
+}
+
+pub fn format_size(size: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+
+    if size < 1024 {
+        return format!("{size} B");
+    }
+    let mut size = size as f64 / 1024.0;
+    let mut unit = UNITS[0];
+    for next_unit in &UNITS[1..] {
+        if size < 1024.0 {
+            break;
+        }
+        size /= 1024.0;
+        unit = next_unit;
+    }
+    format!("{size:.1} {unit}")
+}
+
+/// Format a number of seconds the same way `--fetch-interval` accepts them
+fn format_age(seconds: u64) -> String {
+    match seconds {
+        0..60 => format!("{seconds}s"),
+        60..3600 => format!("{}m", seconds / 60),
+        3600..86400 => format!("{}h", seconds / 3600),
+        _ => format!("{}d", seconds / 86400),
+    }
+}
+
+/// `$XDG_CACHE_HOME/cargo-goggles`, or `~/.cache/cargo-goggles`
+fn default_dir() -> Result<PathBuf> {
+    if let Some(dir) = env::var_os(CACHE_DIR_ENV).filter(|dir| !dir.is_empty()) {
+        return Ok(PathBuf::from(dir));
+    }
+
+    // Relative paths are invalid according to the XDG base directory specification
+    let cache_home = match env::var_os("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .filter(|dir| dir.is_absolute())
+    {
+        Some(cache_home) => cache_home,
+        None => home::home_dir()
+            .context("home directory not found")?
+            .join(".cache"),
+    };
+    Ok(cache_home.join(env!("CARGO_PKG_NAME")))
+}
+
+/// Create `dir`, only accessible by the current user, or check an existing one
+#[cfg(unix)]
+fn create_private_dir(dir: &Path) -> Result<()> {
+    use std::os::unix::fs::{DirBuilderExt as _, MetadataExt as _, PermissionsExt as _};
+
+    use anyhow::ensure;
+
+    fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(dir)?;
+
+    let metadata = fs::metadata(dir)?;
+    // SAFETY: `geteuid` is always successful
+    let uid = unsafe { libc::geteuid() };
+    ensure!(
+        metadata.uid() == uid,
+        "{} is owned by uid {} instead of the current user ({uid})",
+        dir.display(),
+        metadata.uid()
+    );
+    if metadata.mode() & 0o077 != 0 {
+        fs::set_permissions(dir, fs::Permissions::from_mode(0o700))?;
+    }
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn create_private_dir(dir: &Path) -> Result<()> {
+    fs::create_dir_all(dir)?;
+    Ok(())
+}
@@ -0,0 +1,146 @@
+use std::{
+    collections::BTreeMap,
+    env, fs, io,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context as _, Result};
+use serde::Deserialize;
+
+/// The parts of cargo's configuration and credentials describing registries
+#[derive(Debug, Default)]
+pub struct CargoConfig {
+    cargo_home: PathBuf,
+    /// Keyed by the name of the registry as it appears in environment
+    /// variables, so that they refer to the same registry as the files
+    registries: BTreeMap<String, RegistryConfig>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct ConfigFile {
+    #[serde(default)]
+    registries: BTreeMap<String, RegistryConfig>,
+}
+
+#[derive(Debug, Default, Clone, Deserialize)]
+struct RegistryConfig {
+    index: Option<String>,
+    token: Option<String>,
+}
+
+impl CargoConfig {
+    /// Load the configuration the way cargo would when run from `current_dir`
+    ///
+    /// `CARGO_REGISTRIES_<NAME>_INDEX` and `CARGO_REGISTRIES_<NAME>_TOKEN`
+    /// take precedence over the files. Files closer to `current_dir` take
+    /// precedence over the ones further up and over `$CARGO_HOME`, which is
+    /// the only place `credentials.toml` is read from. Credential providers
+    /// aren't supported, only plain tokens.
+    pub fn load(current_dir: &Path) -> Result<Self> {
+        let cargo_home = home::cargo_home_with_cwd(current_dir).context("find cargo home")?;
+
+        let mut files = current_dir
+            .ancestors()
+            .map(|dir| dir.join(".cargo"))
+            .filter(|dir| *dir != cargo_home)
+            .collect::<Vec<_>>();
+        files.push(cargo_home.clone());
+
+        let mut config = Self {
+            cargo_home,
+            ..Self::default()
+        };
+        config.merge(env_config_file());
+        for dir in files {
+            let names = if dir == config.cargo_home {
+                &["config.toml", "config", "credentials.toml", "credentials"][..]
+            } else {
+                &["config.toml", "config"][..]
+            };
+            for name in names {
+                if let Some(file) = read_config_file(&dir.join(name))? {
+                    config.merge(file);
+                }
+            }
+        }
+
+        Ok(config)
+    }
+
+    /// `$CARGO_HOME`, or its default location
+    pub fn cargo_home(&self) -> &Path {
+        &self.cargo_home
+    }
+
+    /// Find the token to use for the registry with the given index url
+    ///
+    /// `index` is expected in the same form as the `index` field of cargo's
+    /// configuration, that is with the `sparse+` prefix for sparse registries.
+    pub fn token(&self, index: &str) -> Option<String> {
+        self.registries
+            .values()
+            .find(|registry| {
+                registry
+                    .index
+                    .as_deref()
+                    .is_some_and(|registry_index| same_index(registry_index, index))
+            })?
+            .token
+            .clone()
+    }
+
+    fn merge(&mut self, file: ConfigFile) {
+        for (name, registry) in file.registries {
+            let entry = self
+                .registries
+                .entry(name.to_uppercase().replace('-', "_"))
+                .or_default();
+            if entry.index.is_none() {
+                entry.index = registry.index;
+            }
+            if entry.token.is_none() {
+                entry.token = registry.token;
+            }
+        }
+    }
+}
+
+/// Registries declared through `CARGO_REGISTRIES_<NAME>_INDEX` and
+/// `CARGO_REGISTRIES_<NAME>_TOKEN`
+fn env_config_file() -> ConfigFile {
+    let mut file = ConfigFile::default();
+    for (var, value) in env::vars_os() {
+        let (Some(var), Some(value)) = (var.to_str(), value.to_str()) else {
+            continue;
+        };
+        let Some(var) = var.strip_prefix("CARGO_REGISTRIES_") else {
+            continue;
+        };
+        if let Some(name) = var.strip_suffix("_INDEX") {
+            let registry = file.registries.entry(name.to_owned()).or_default();
+            registry.index = Some(value.to_owned());
+        } else if let Some(name) = var.strip_suffix("_TOKEN") {
+            let registry = file.registries.entry(name.to_owned()).or_default();
+            registry.token = Some(value.to_owned());
+        }
+    }
+    file
+}
+
+fn read_config_file(path: &Path) -> Result<Option<ConfigFile>> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => {
+            return Err(err).with_context(|| format!("read {}", path.display()));
+        }
+    };
+
+    toml::from_str(&contents)
+        .with_context(|| format!("parse {}", path.display()))
+        .map(Some)
+}
+
+fn same_index(a: &str, b: &str) -> bool {
+    a.trim_end_matches('/') == b.trim_end_matches('/')
+}
@@ -0,0 +1,221 @@
+use std::{path::PathBuf, str::FromStr, time::Duration};
+
+use anyhow::{bail, Context as _, Error};
+use clap::{ArgGroup, Args, Parser, Subcommand, ValueEnum};
+use semver::Version;
+
+use crate::policy::Selector;
+
+#[derive(Debug, Parser)]
+#[command(name = "cargo", bin_name = "cargo")]
+pub enum Cli {
+    Goggles(GogglesArgs),
+}
+
+/// Verify that registry crates in your Cargo.lock are reproducible from the git repository
+#[derive(Debug, Args)]
+#[command(version, about)]
+pub struct GogglesArgs {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// Format of the report printed to stdout
+    #[arg(long, value_enum, default_value_t = OutputFormat::Human, global = true)]
+    pub format: OutputFormat,
+
+    /// Print a unified diff for every file differing between the repository and the registry
+    #[arg(long)]
+    pub show_diff: bool,
+
+    /// Exit with a non-zero status when any of these finding kinds or verdicts are produced
+    ///
+    /// Accepts finding kinds (e.g. `missing-tag`) and verdicts (`mismatch`,
+    /// `unverifiable`, `reproducible-with-warnings`, `skipped`).
+    #[arg(long, value_name = "KIND", value_delimiter = ',')]
+    pub deny: Vec<Selector>,
+
+    /// Only warn about these finding kinds or verdicts, overriding `--deny`
+    #[arg(long, value_name = "KIND", value_delimiter = ',')]
+    pub warn: Vec<Selector>,
+
+    /// Read tag patterns and per-crate overrides from this file
+    ///
+    /// Defaults to `cargo-goggles.toml` in the current directory, if present.
+    #[arg(long, value_name = "FILE", global = true)]
+    pub config: Option<PathBuf>,
+
+    /// Keep downloaded crates and cloned repositories in this directory
+    ///
+    /// Defaults to `$CARGO_GOGGLES_CACHE_DIR`, or to `cargo-goggles` in the
+    /// user's cache directory (`$XDG_CACHE_HOME` or `~/.cache`).
+    #[arg(long, value_name = "DIR", global = true)]
+    pub cache_dir: Option<PathBuf>,
+
+    /// Never use the network, only local `.crate` files and repositories
+    ///
+    /// Packages which aren't available locally get an `unavailable-offline`
+    /// finding.
+    #[arg(long, global = true)]
+    pub offline: bool,
+
+    /// Look for `<name>-<version>.crate` files in this directory before downloading them
+    #[arg(long, value_name = "DIR", global = true)]
+    pub crates_dir: Vec<PathBuf>,
+
+    /// Look for packages in this directory populated by `cargo vendor`
+    #[arg(long, value_name = "DIR", global = true)]
+    pub vendor_dir: Option<PathBuf>,
+
+    /// Clone repositories from this directory of clones, laid out as `<host>/<path>`
+    #[arg(long, value_name = "DIR", global = true)]
+    pub repositories_dir: Option<PathBuf>,
+
+    /// Fetch previously cloned repositories when they were last fetched longer ago than this
+    ///
+    /// Accepts a number of seconds, or a number followed by `s`, `m`, `h` or `d`.
+    #[arg(long, value_name = "DURATION", default_value = "1h", value_parser = parse_duration, global = true)]
+    pub fetch_interval: Duration,
+
+    /// Never fetch previously cloned repositories
+    #[arg(long, global = true)]
+    pub no_fetch: bool,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Print a unified diff between the repository and registry releases of a package
+    Diff {
+        /// The package to diff, as `<name>@<version>` or just `<name>`
+        package: PackageSpec,
+    },
+    /// Inspect and clean up the downloaded crates and cloned repositories
+    Cache {
+        #[command(subcommand)]
+        command: CacheCommand,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum CacheCommand {
+    /// List every crate, registry index and repository in the cache
+    List,
+    /// Print how much space the cache takes
+    Size,
+    /// Remove entries which weren't used recently or aren't needed by a lockfile
+    ///
+    /// When both conditions are given, only the entries matching both are
+    /// removed. Leftovers of interrupted runs are always removed.
+    #[command(group(ArgGroup::new("condition").required(true).multiple(true)))]
+    Prune {
+        /// Remove entries last used longer ago than this
+        #[arg(long, value_name = "DURATION", value_parser = parse_duration, group = "condition")]
+        older_than: Option<Duration>,
+        /// Remove crates and repositories which aren't needed to analyze this `Cargo.lock`
+        #[arg(long, value_name = "CARGO_LOCK", group = "condition")]
+        unused_by: Option<PathBuf>,
+    },
+    /// Remove everything from the cache
+    Clean,
+}
+
+/// A package in Cargo.lock, optionally qualified by its version
+#[derive(Debug, Clone)]
+pub struct PackageSpec {
+    pub name: String,
+    pub version: Option<Version>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    /// Human readable lines
+    Human,
+    /// A single versioned JSON document covering the whole run
+    Json,
+}
+
+fn parse_duration(s: &str) -> Result<Duration, Error> {
+    let (number, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => s.split_at(i),
+        None => (s, "s"),
+    };
+    let number = number.parse::<u64>().context("invalid duration")?;
+    let multiplier = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 60 * 60 * 24,
+        _ => bail!("unknown duration unit `{unit}`"),
+    };
+    let seconds = number.checked_mul(multiplier).context("invalid duration")?;
+    Ok(Duration::from_secs(seconds))
+}
+
+impl PackageSpec {
+    pub fn matches(&self, package: &cargo_lock::Package) -> bool {
+        package.name.as_str() == self.name
+            && self
+                .version
+                .as_ref()
+                .is_none_or(|version| *version == package.version)
+    }
+}
+
+impl FromStr for PackageSpec {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.split_once('@') {
+            Some((name, version)) => Self {
+                name: name.to_owned(),
+                version: Some(version.parse().context("invalid package version")?),
+            },
+            None => Self {
+                name: s.to_owned(),
+                version: None,
+            },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_durations() {
+        let cases = [
+            ("0", 0),
+            ("90", 90),
+            ("90s", 90),
+            ("15m", 15 * 60),
+            ("1h", 60 * 60),
+            ("7d", 7 * 24 * 60 * 60),
+            ("18446744073709551615", u64::MAX),
+        ];
+        for (s, seconds) in cases {
+            assert_eq!(
+                parse_duration(s).unwrap(),
+                Duration::from_secs(seconds),
+                "{s}"
+            );
+        }
+    }
+
+    #[test]
+    fn parse_invalid_durations() {
+        for s in [
+            "",
+            "h",
+            "-1h",
+            "1.5h",
+            "1 h",
+            "1w",
+            "1hs",
+            "18446744073709551616",
+            "18446744073709551615m",
+            "213503982334602d",
+        ] {
+            assert!(parse_duration(s).is_err(), "{s}");
+        }
+    }
+}
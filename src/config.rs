@@ -0,0 +1,167 @@
+use std::{collections::BTreeMap, fs, io, path::Path};
+
+use anyhow::{ensure, Context as _, Result};
+use serde::Deserialize;
+
+use crate::git::{RepositoryProtocol, UrlRewrites};
+
+/// Name of the configuration file looked up in the current directory
+pub const DEFAULT_CONFIG_FILE: &str = "cargo-goggles.toml";
+
+/// Tag patterns tried after the configured ones
+const DEFAULT_TAG_PATTERNS: [&str; 12] = [
+    // With package name prefix
+    "{name}-v{version}",
+    "{name}-{version}",
+    "{name}_v{version}",
+    "{name}_{version}",
+    "{name}/v{version}",
+    "{name}v/{version}",
+    "{name}/{version}",
+    "{name}@v{version}",
+    "{name}@{version}",
+    // Just the version
+    "v{version}",
+    "{version}",
+    "v/{version}",
+];
+
+/// The `cargo-goggles.toml` configuration file
+///
+/// ```toml
+/// tag-patterns = ["release/{version}"]
+/// repository-protocol = "preserve"
+///
+/// [crates.tokio-macros]
+/// tag-patterns = ["tokio-macros-{version}"]
+///
+/// [url."https://git.internal/mirror/github/"]
+/// instead-of = ["https://github.com/"]
+/// ```
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Config {
+    /// Tag patterns tried for every crate, before the built-in ones
+    #[serde(default)]
+    tag_patterns: Vec<String>,
+    /// How `ssh` and `git` repository urls are fetched
+    #[serde(default)]
+    repository_protocol: RepositoryProtocol,
+    #[serde(default)]
+    crates: BTreeMap<String, CrateConfig>,
+    /// Repository url rewrites, keyed by the replacement
+    #[serde(default)]
+    url: BTreeMap<String, UrlConfig>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct CrateConfig {
+    /// Tag patterns tried for this crate, before the global ones
+    #[serde(default)]
+    tag_patterns: Vec<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct UrlConfig {
+    /// Url prefixes replaced with the key of the section
+    instead_of: Vec<String>,
+}
+
+impl Config {
+    /// Load the configuration from `path`, or from `cargo-goggles.toml` in `current_dir`
+    ///
+    /// Only a missing default file is allowed, an explicitly given one must exist.
+    pub fn load(path: Option<&Path>, current_dir: &Path) -> Result<Self> {
+        let (path, required) = match path {
+            Some(path) => (path.to_owned(), true),
+            None => (current_dir.join(DEFAULT_CONFIG_FILE), false),
+        };
+
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == io::ErrorKind::NotFound && !required => {
+                return Ok(Self::default())
+            }
+            Err(err) => return Err(err).with_context(|| format!("read {}", path.display())),
+        };
+
+        let config = toml::from_str::<Self>(&contents)
+            .with_context(|| format!("parse {}", path.display()))?;
+        config
+            .validate()
+            .with_context(|| format!("invalid {}", path.display()))?;
+        Ok(config)
+    }
+
+    /// The tag patterns to try for `name`, in order of preference
+    ///
+    /// `{name}` and `{version}` are replaced with the name and the version of
+    /// the crate. Crate sections are matched the same way tags are, ignoring
+    /// case and the difference between `-` and `_`.
+    pub fn tag_patterns<'a>(&'a self, name: &str) -> impl Iterator<Item = &'a str> {
+        let crate_config = self.crates.get(name).or_else(|| {
+            self.crates
+                .iter()
+                .find(|(crate_name, _)| normalize(crate_name) == normalize(name))
+                .map(|(_, crate_config)| crate_config)
+        });
+
+        crate_config
+            .into_iter()
+            .flat_map(|crate_config| &crate_config.tag_patterns)
+            .chain(&self.tag_patterns)
+            .map(String::as_str)
+            .chain(DEFAULT_TAG_PATTERNS)
+    }
+
+    pub fn repository_protocol(&self) -> RepositoryProtocol {
+        self.repository_protocol
+    }
+
+    /// Rewrites applied to repository urls before fetching them
+    pub fn url_rewrites(&self) -> UrlRewrites {
+        UrlRewrites::new(self.url.iter().flat_map(|(replacement, url_config)| {
+            url_config
+                .instead_of
+                .iter()
+                .map(|prefix| (prefix.clone(), replacement.clone()))
+        }))
+    }
+
+    fn validate(&self) -> Result<()> {
+        let patterns = self.tag_patterns.iter().chain(
+            self.crates
+                .values()
+                .flat_map(|crate_config| &crate_config.tag_patterns),
+        );
+        for pattern in patterns {
+            ensure!(
+                pattern.contains("{version}"),
+                "tag pattern `{pattern}` doesn't contain `{{version}}`"
+            );
+        }
+
+        for (replacement, url_config) in &self.url {
+            ensure!(
+                !url_config.instead_of.is_empty(),
+                "`url.\"{replacement}\"` has no `instead-of` prefix"
+            );
+            ensure!(
+                url_config
+                    .instead_of
+                    .iter()
+                    .all(|prefix| !prefix.is_empty()),
+                "`url.\"{replacement}\"` has an empty `instead-of` prefix"
+            );
+        }
+
+        Ok(())
+    }
+}
+
+/// Make names which only differ by case or by `-` and `_` equal
+pub fn normalize(name: &str) -> String {
+    name.to_lowercase().replace('_', "-")
+}
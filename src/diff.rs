@@ -0,0 +1,58 @@
+use std::{path::Path, str};
+
+use serde::Serialize;
+use similar::TextDiff;
+
+/// How many bytes to look at when guessing whether a file is binary, like git does
+const BINARY_DETECTION_LEN: usize = 8000;
+
+#[derive(Debug, Serialize)]
+pub struct FileDiff {
+    pub path: String,
+    /// Unified diff from the repository package to the registry package
+    pub diff: String,
+}
+
+impl FileDiff {
+    /// Diff a file between the repository package and the registry package
+    ///
+    /// A file missing on one side is diffed against `/dev/null`, so that its
+    /// contents are shown in full. Binary files are only summarized.
+    pub fn new(path: &Path, repository: Option<&[u8]>, registry: Option<&[u8]>) -> Self {
+        let path = path.display().to_string();
+        let old_header = match repository {
+            Some(_) => format!("repository/{path}"),
+            None => "/dev/null".to_owned(),
+        };
+        let new_header = match registry {
+            Some(_) => format!("registry/{path}"),
+            None => "/dev/null".to_owned(),
+        };
+
+        let old = repository.unwrap_or_default();
+        let new = registry.unwrap_or_default();
+        let diff = match (text(old), text(new)) {
+            (Some(old), Some(new)) => TextDiff::from_lines(old, new)
+                .unified_diff()
+                .context_radius(3)
+                .header(&old_header, &new_header)
+                .to_string(),
+            _ => format!(
+                "Binary files {old_header} ({} bytes) and {new_header} ({} bytes) differ\n",
+                old.len(),
+                new.len()
+            ),
+        };
+
+        Self { path, diff }
+    }
+}
+
+fn text(contents: &[u8]) -> Option<&str> {
+    let head = &contents[..contents.len().min(BINARY_DETECTION_LEN)];
+    if head.contains(&0) {
+        return None;
+    }
+
+    str::from_utf8(contents).ok()
+}
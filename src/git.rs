@@ -1,34 +1,159 @@
 use std::{
     cmp::Ordering,
-    collections::BTreeSet,
+    collections::{BTreeSet, HashMap},
     fmt::{self, Display},
+    fs, io, mem,
+    num::NonZeroU32,
     path::{Path, PathBuf},
-    process::Command,
+    process::{self, Command},
     str,
+    sync::atomic::{self, AtomicBool, AtomicUsize},
+    time::{Duration, SystemTime},
 };
 
-use anyhow::{ensure, Context as _, Result};
+use anyhow::{anyhow, bail, ensure, Context as _, Result};
+use gix::{
+    bstr::ByteSlice as _,
+    remote::{
+        fetch::{Shallow, Tags},
+        Direction,
+    },
+    ObjectId,
+};
 use semver::Version;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest as _, Sha256};
 use url::Url;
 
+use crate::config;
 use crate::package::Package;
+use crate::verdict::FindingKind;
+
+/// Never prompt for credentials, the same way `GIT_TERMINAL_PROMPT=0` would
+const CONFIG_OVERRIDES: [&str; 1] = ["gitoxide.credentials.terminalPrompt=false"];
+/// Touched every time a repository is cloned or fetched
+pub const FETCH_MARKER: &str = "cargo-goggles-fetch";
+/// Records the url a clone was obtained for, as its directory name is hashed,
+/// and is rewritten every time the clone is used
+pub const URL_FILE: &str = "cargo-goggles-url";
+
+/// Distinguishes the scratch directories created concurrently by this process
+static SCRATCH_COUNTER: AtomicUsize = AtomicUsize::new(0);
 
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub struct GitUrl(Url);
 
-#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+/// Where and how repositories are obtained
+#[derive(Debug, Clone, Default)]
+pub struct GitRepositorySources {
+    /// A directory of pre-populated clones, laid out as `<host>/<path>`
+    pub mirror_dir: Option<PathBuf>,
+    /// Never use the network, only local clones
+    pub offline: bool,
+    /// How long a cached clone is considered up to date, `None` to never fetch it
+    pub fetch_interval: Option<Duration>,
+    /// How `ssh` and `git` repository urls are fetched
+    pub protocol: RepositoryProtocol,
+    /// Where repositories are actually fetched from
+    pub rewrites: UrlRewrites,
+}
+
+/// Rewrites of repository urls, the same way git's `url.<base>.insteadOf` does
+#[derive(Debug, Clone, Default)]
+pub struct UrlRewrites(Vec<(String, String)>);
+
+/// How repository urls using the `ssh` or `git` protocols are fetched
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RepositoryProtocol {
+    /// Fetch them over https from the same host, which doesn't need any key
+    #[default]
+    Https,
+    /// Fetch them with the protocol they declare
+    Preserve,
+}
+
+/// A bare clone of a repository, or a repository in the mirror directory
+#[derive(Debug)]
 pub struct GitRepository {
-    repo_dir: PathBuf,
+    repo: gix::ThreadSafeRepository,
+    url: Url,
+    /// `url` with the rewrites applied
+    fetch_url: String,
+    /// Where submodules are cloned
+    repositories_dir: PathBuf,
+    /// Where commits are exported to be packaged, one directory per checkout
+    checkouts_dir: PathBuf,
+    sources: GitRepositorySources,
+    /// Whether `repo` lives in the mirror directory, which is never fetched
+    mirror: bool,
 }
 
+/// A commit exported into its own directory, which is removed once dropped
 #[derive(Debug)]
 pub struct GitRepositoryCheckout<'a> {
     repository: &'a GitRepository,
+    dir: PathBuf,
 }
 
 #[derive(Debug)]
 pub struct GitTags<'a>(BTreeSet<GitTag<'a>>);
 
+/// The parts of a `Cargo.toml` needed to find out which version it declares
+#[derive(Debug, Deserialize)]
+struct VersionManifest {
+    package: Option<VersionManifestPackage>,
+    workspace: Option<VersionManifestWorkspace>,
+}
+
+#[derive(Debug, Deserialize)]
+struct VersionManifestPackage {
+    name: String,
+    version: Option<ManifestVersion>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+enum ManifestVersion {
+    Version(String),
+    Inherited { workspace: bool },
+}
+
+#[derive(Debug, Deserialize)]
+struct VersionManifestWorkspace {
+    package: Option<VersionManifestWorkspacePackage>,
+}
+
+#[derive(Debug, Deserialize)]
+struct VersionManifestWorkspacePackage {
+    version: Option<String>,
+}
+
+/// Where a commit sits in the history relative to another one
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "relation", rename_all = "kebab-case")]
+pub enum CommitRelationship {
+    /// The commit is an ancestor of the other one, `distance` commits behind it
+    Ancestor { distance: usize },
+    /// The other commit is an ancestor of this one, `distance` commits behind it
+    Descendant { distance: usize },
+    /// Neither commit is an ancestor of the other, each has its own commits
+    /// since they diverged
+    Diverged { ahead: usize, behind: usize },
+}
+
+/// Whether a commit is part of a repository
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CommitStatus {
+    /// The commit is an ancestor of a branch or a tag
+    Reachable,
+    /// The commit exists, but no branch or tag leads to it anymore
+    Unreachable,
+    /// The commit isn't in the repository
+    Missing,
+}
+
 #[derive(Debug)]
 pub struct GitTag<'a> {
     repository: &'a GitRepository,
@@ -36,142 +161,724 @@ pub struct GitTag<'a> {
 }
 
 impl GitRepository {
-    pub fn obtain(dir: &Path, GitUrl(url): GitUrl) -> Result<Self> {
-        let name = format!("{}-{}", url.host().unwrap(), url.path().replace('/', "-"));
-        let repo_dir = dir.join(name);
-        if !repo_dir.try_exists()? {
-            let out = Command::new("git")
-                .arg("clone")
-                .arg("--filter=blob:none")
-                .arg("--")
-                .arg(url.to_string())
-                .arg(&repo_dir)
-                .env("GIT_TERMINAL_PROMPT", "0")
-                .output()?;
-            ensure!(out.status.success(), "`git clone` is successful");
+    /// Clone the repository into `dir`, unless it was cloned by a previous run
+    ///
+    /// A clone found in the mirror directory is used in place of the network,
+    /// which isn't used at all in offline mode.
+    pub fn obtain(dir: &Path, sources: &GitRepositorySources, GitUrl(url): GitUrl) -> Result<Self> {
+        let host = url.host_str().unwrap();
+        let name = cache_key(&url);
+        let repo_dir = dir.join(&name);
+        let checkouts_dir = dir.join(format!("{name}.checkouts"));
+        let fetch_url = sources.rewrites.rewrite(url.as_str());
+
+        let mut mirror = None;
+        if let Some(mirror_dir) = &sources.mirror_dir {
+            let path = url.path().trim_start_matches('/');
+            for candidate in [path, path.trim_end_matches(".git")] {
+                let candidate = mirror_dir.join(host).join(candidate);
+                if candidate.try_exists()? {
+                    mirror = Some(candidate);
+                    break;
+                }
+            }
         }
 
-        Ok(Self { repo_dir })
+        let repo = if let Some(mirror) = &mirror {
+            open(mirror).context("open mirrored repository")?
+        } else if repo_dir.try_exists()? {
+            let repo = open(&repo_dir).context("open cloned repository")?;
+            fs::write(repo_dir.join(URL_FILE), url.as_str())?;
+            repo
+        } else if sources.offline {
+            return Err(
+                anyhow!("{url} isn't available locally").context(FindingKind::UnavailableOffline)
+            );
+        } else {
+            clone(
+                &fetch_url,
+                &repo_dir,
+                Tags::All,
+                Shallow::NoChange,
+                &[(URL_FILE, url.as_str()), (FETCH_MARKER, "")],
+            )
+            .with_context(|| format!("clone repository from {fetch_url}"))?;
+            open(&repo_dir).context("open cloned repository")?
+        };
+
+        Ok(Self {
+            repo: repo.into_sync(),
+            url,
+            fetch_url,
+            repositories_dir: dir.to_owned(),
+            checkouts_dir,
+            sources: sources.clone(),
+            mirror: mirror.is_some(),
+        })
+    }
+
+    /// Fetch branches and tags, unless the last fetch is more recent than `interval`
+    ///
+    /// Tags which were moved or deleted upstream are updated accordingly. The
+    /// url is the rewritten one, not the one `origin` was cloned from.
+    pub fn fetch(&self, interval: Duration) -> Result<()> {
+        if self.mirror {
+            return Ok(());
+        }
+
+        let repo = self.repo.to_thread_local();
+        let marker = repo.git_dir().join(FETCH_MARKER);
+        match fs::metadata(&marker) {
+            Ok(metadata) => {
+                let age = SystemTime::now()
+                    .duration_since(metadata.modified()?)
+                    .unwrap_or_default();
+                if age < interval {
+                    return Ok(());
+                }
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err.into()),
+        }
+
+        let outcome = repo
+            .find_remote("origin")?
+            .with_url_without_url_rewrite(self.fetch_url.as_str())?
+            .with_refspecs(
+                [
+                    "+HEAD:refs/remotes/origin/HEAD",
+                    "+refs/heads/*:refs/remotes/origin/*",
+                    "+refs/tags/*:refs/tags/*",
+                ],
+                Direction::Fetch,
+            )?
+            .with_fetch_tags(Tags::All)
+            .connect(Direction::Fetch)?
+            .prepare_fetch(gix::progress::Discard, Default::default())?
+            .receive(gix::progress::Discard, &AtomicBool::new(false))
+            .context("fetch repository")?;
+
+        let remote_tags = outcome
+            .ref_map
+            .remote_refs
+            .iter()
+            .map(|remote_ref| remote_ref.unpack().0)
+            .filter(|name| name.starts_with(b"refs/tags/"))
+            .collect::<BTreeSet<_>>();
+        for tag in repo.references()?.tags()? {
+            let tag = tag.map_err(|err| anyhow!(err))?;
+            if !remote_tags.contains(tag.name().as_bstr()) {
+                tag.delete()?;
+            }
+        }
+
+        fs::write(marker, "")?;
+        Ok(())
     }
 
+    // `GitTag` is ordered by name only, the repository it borrows doesn't matter
+    #[allow(clippy::mutable_key_type)]
     pub fn tags(&self) -> Result<GitTags<'_>> {
-        let out = Command::new("git")
-            .arg("tag")
-            .current_dir(&self.repo_dir)
-            .output()?;
-        ensure!(out.status.success(), "`git tag` is successful");
-
-        let tags = str::from_utf8(&out.stdout)
-            .context("couldn't parse git tags")?
-            .lines()
-            .map(|tag| GitTag {
-                repository: self,
-                tag: tag.to_owned(),
+        let tags = self
+            .repo
+            .to_thread_local()
+            .references()?
+            .tags()?
+            .map(|tag| {
+                let tag = tag.map_err(|err| anyhow!(err))?;
+                Ok(GitTag {
+                    repository: self,
+                    tag: tag.name().shorten().to_str()?.to_owned(),
+                })
             })
-            .collect::<BTreeSet<_>>();
+            .collect::<Result<BTreeSet<_>>>()
+            .context("couldn't list git tags")?;
         Ok(GitTags(tags))
     }
 
-    pub fn checkout<'a>(&'a mut self, commit: &str) -> Result<GitRepositoryCheckout<'a>> {
-        let out = Command::new("git")
-            .arg("checkout")
-            .arg(commit)
-            .current_dir(&self.repo_dir)
-            .output()
-            .context("checkout the commit")?;
-        ensure!(out.status.success(), "`git checkout` is successful");
-
-        let out = Command::new("git")
-            .arg("submodule")
-            .arg("init")
-            .env("GIT_TERMINAL_PROMPT", "0")
-            .current_dir(&self.repo_dir)
-            .output()
-            .context("init submodules")?;
-        ensure!(out.status.success(), "`git submodule init` is successful");
-
-        let out = Command::new("git")
-            .arg("submodule")
-            .arg("sync")
-            .env("GIT_TERMINAL_PROMPT", "0")
-            .current_dir(&self.repo_dir)
-            .output()
-            .context("sync submodules")?;
-        ensure!(out.status.success(), "`git submodule sync` is successful");
-
-        let out = Command::new("git")
-            .arg("submodule")
-            .arg("update")
-            .env("GIT_TERMINAL_PROMPT", "0")
-            .current_dir(&self.repo_dir)
-            .output()
-            .context("update submodules")?;
-        ensure!(out.status.success(), "`git submodule update` is successful");
+    /// Check out `commit`, including submodules, into a new scratch directory
+    ///
+    /// Every checkout gets its own directory, so that several commits of the
+    /// same repository can be checked out and packaged concurrently.
+    pub fn checkout(&self, commit: &str) -> Result<GitRepositoryCheckout<'_>> {
+        let commit = ObjectId::from_hex(commit.as_bytes()).context("invalid commit id")?;
 
-        Ok(GitRepositoryCheckout { repository: self })
+        fs::create_dir_all(&self.checkouts_dir).context("create checkouts directory")?;
+        let dir = self
+            .checkouts_dir
+            .join(format!("{commit}.{}", scratch_suffix()));
+        if dir.try_exists()? {
+            fs::remove_dir_all(&dir).context("clean checkout directory")?;
+        }
+
+        let checkout = GitRepositoryCheckout {
+            repository: self,
+            dir,
+        };
+        self.export(commit, &checkout.dir)?;
+        Ok(checkout)
     }
+
+    /// Check out `commit` into `dest`, the way `git clone --shared` would
+    ///
+    /// `dest` becomes a repository borrowing the objects of this one, so that
+    /// `cargo package` sees a regular clean checkout without this repository
+    /// being modified. Submodules are checked out the same way from their own
+    /// clones.
+    fn export(&self, commit: ObjectId, dest: &Path) -> Result<()> {
+        let repo = self.repo.to_thread_local();
+        let tree = repo
+            .find_commit(commit)
+            .context("commit not found")?
+            .tree_id()?;
+
+        let git_dir = gix::init(dest)?.git_dir().to_owned();
+        let objects_dir = repo
+            .common_dir()
+            .join("objects")
+            .canonicalize()
+            .context("canonicalize objects directory")?;
+        fs::write(
+            git_dir.join("objects").join("info").join("alternates"),
+            format!("{}\n", objects_dir.display()),
+        )?;
+        fs::write(git_dir.join("HEAD"), format!("{commit}\n"))?;
+
+        let checkout = open(dest)?;
+        let mut index = checkout.index_from_tree(&tree)?;
+        let mut options = checkout
+            .checkout_options(gix::worktree::stack::state::attributes::Source::IdMapping)?;
+        options.destination_is_initially_empty = true;
+        gix::worktree::state::checkout(
+            &mut index,
+            dest,
+            checkout.objects.clone().into_arc()?,
+            &gix::progress::Discard,
+            &gix::progress::Discard,
+            &AtomicBool::new(false),
+            options,
+        )
+        .context("check out files")?;
+        index.write(Default::default())?;
+
+        let submodules = index
+            .entries()
+            .iter()
+            .filter(|entry| entry.mode == gix::index::entry::Mode::COMMIT)
+            .map(|entry| (entry.path(&index).to_owned(), entry.id))
+            .collect::<Vec<_>>();
+        if submodules.is_empty() {
+            return Ok(());
+        }
+
+        let gitmodules = fs::read(dest.join(".gitmodules")).context("read .gitmodules")?;
+        let gitmodules = gix::submodule::File::from_bytes(&gitmodules, None, &Default::default())
+            .context("parse .gitmodules")?;
+        for (path, commit) in submodules {
+            let name = gitmodules
+                .name_by_path(path.as_bstr())
+                .with_context(|| format!("submodule {path} not found in .gitmodules"))?;
+            let url = gitmodules
+                .config()
+                .string(format!("submodule.{name}.url").as_str())
+                .with_context(|| format!("submodule {name} doesn't have a url"))?;
+            let url = self.submodule_url(url.to_str()?)?;
+
+            let submodule = GitRepository::obtain(&self.repositories_dir, &self.sources, url)
+                .with_context(|| format!("obtain submodule {name}"))?;
+            if let Some(fetch_interval) = self.sources.fetch_interval {
+                if !submodule.has_commit(commit) {
+                    submodule.fetch(fetch_interval)?;
+                }
+            }
+
+            let submodule_dir = dest.join(gix::path::from_bstr(path.as_bstr())?);
+            if submodule_dir.try_exists()? {
+                fs::remove_dir(&submodule_dir)?;
+            }
+            submodule
+                .export(commit, &submodule_dir)
+                .with_context(|| format!("check out submodule {name}"))?;
+        }
+
+        Ok(())
+    }
+
+    fn has_commit(&self, commit: ObjectId) -> bool {
+        self.repo.to_thread_local().find_commit(commit).is_ok()
+    }
+
+    /// Find out whether `commit` exists and can be reached from any branch or tag
+    pub fn commit_status(&self, commit: &str) -> Result<CommitStatus> {
+        let commit = ObjectId::from_hex(commit.as_bytes()).context("invalid commit id")?;
+        if !self.has_commit(commit) {
+            return Ok(CommitStatus::Missing);
+        }
+
+        let repo = self.repo.to_thread_local();
+        for info in repo.rev_walk(tips(&repo)?).all()? {
+            if info?.id == commit {
+                return Ok(CommitStatus::Reachable);
+            }
+        }
+        Ok(CommitStatus::Unreachable)
+    }
+
+    /// Find the commits at which the manifest of `name` declares `version`, oldest first
+    ///
+    /// Manifests are looked up at the paths where `name` is declared on the
+    /// default branch, following `version.workspace = true` to the root
+    /// manifest. Every commit reachable from a branch or a tag is searched.
+    /// Commits are grouped in runs of consecutive commits, by commit time,
+    /// declaring the version.
+    pub fn find_version_commits(&self, name: &str, version: &Version) -> Result<Vec<Vec<String>>> {
+        let repo = self.repo.to_thread_local();
+
+        // The local `HEAD` of a clone isn't moved by later fetches
+        let default_branch = match repo.try_find_reference("refs/remotes/origin/HEAD")? {
+            Some(mut reference) => reference.peel_to_commit(),
+            None => repo.head_commit(),
+        }
+        .context("find default branch")?;
+        let manifest_files = default_branch
+            .tree()?
+            .traverse()
+            .breadthfirst
+            .files()?
+            .into_iter()
+            .filter(|entry| {
+                entry.mode.is_blob()
+                    && (entry.filepath == "Cargo.toml" || entry.filepath.ends_with(b"/Cargo.toml"))
+            })
+            .collect::<Vec<_>>();
+        let declaring_files = manifest_files
+            .iter()
+            .filter(|entry| {
+                read_manifest(&repo, entry.oid)
+                    .and_then(|manifest| manifest.package)
+                    .is_some_and(|package| package.name == name)
+            })
+            .collect::<Vec<_>>();
+        // The package may have been renamed or removed since then
+        let manifest_paths = if declaring_files.is_empty() {
+            manifest_files.iter().collect::<Vec<_>>()
+        } else {
+            declaring_files
+        }
+        .into_iter()
+        .map(|entry| Ok(gix::path::from_bstr(entry.filepath.as_bstr())?.into_owned()))
+        .collect::<Result<Vec<_>>>()?;
+
+        let mut declared_versions = HashMap::<ObjectId, Option<ManifestVersion>>::new();
+        let mut workspace_versions = HashMap::<ObjectId, Option<String>>::new();
+        let mut runs = Vec::new();
+        let mut run = Vec::new();
+        let walk = repo
+            .rev_walk(tips(&repo)?)
+            .sorting(gix::revision::walk::Sorting::ByCommitTime(
+                Default::default(),
+            ))
+            .all()?;
+        for info in walk {
+            let commit = info?.id;
+            let tree = repo.find_commit(commit)?.tree()?;
+
+            let mut declares = false;
+            for path in &manifest_paths {
+                let Some(entry) = tree.lookup_entry_by_path(path)? else {
+                    continue;
+                };
+                let declared_version = declared_versions
+                    .entry(entry.object_id())
+                    .or_insert_with(|| {
+                        read_manifest(&repo, entry.object_id())
+                            .and_then(|manifest| manifest.package)
+                            .filter(|package| package.name == name)
+                            .and_then(|package| package.version)
+                    })
+                    .clone();
+
+                let declared_version = match declared_version {
+                    Some(ManifestVersion::Version(declared_version)) => Some(declared_version),
+                    Some(ManifestVersion::Inherited { workspace: true }) => {
+                        match tree.lookup_entry_by_path("Cargo.toml")? {
+                            Some(root) => workspace_versions
+                                .entry(root.object_id())
+                                .or_insert_with(|| {
+                                    read_manifest(&repo, root.object_id())
+                                        .and_then(|manifest| manifest.workspace)
+                                        .and_then(|workspace| workspace.package)
+                                        .and_then(|package| package.version)
+                                })
+                                .clone(),
+                            None => None,
+                        }
+                    }
+                    Some(ManifestVersion::Inherited { workspace: false }) | None => None,
+                };
+                if declared_version.is_some_and(|declared_version| {
+                    declared_version.parse::<Version>().ok().as_ref() == Some(version)
+                }) {
+                    declares = true;
+                    break;
+                }
+            }
+
+            if declares {
+                run.push(commit.to_string());
+            } else if !run.is_empty() {
+                runs.push(mem::take(&mut run));
+            }
+        }
+        if !run.is_empty() {
+            runs.push(run);
+        }
+
+        runs.reverse();
+        for run in &mut runs {
+            run.reverse();
+        }
+        Ok(runs)
+    }
+
+    /// Find out where `commit` sits in the history relative to `other`
+    pub fn relationship(&self, commit: &str, other: &str) -> Result<CommitRelationship> {
+        let commit = ObjectId::from_hex(commit.as_bytes()).context("invalid commit id")?;
+        let other = ObjectId::from_hex(other.as_bytes()).context("invalid commit id")?;
+
+        let repo = self.repo.to_thread_local();
+        let count_only_in = |tip: ObjectId, hidden: ObjectId| -> Result<usize> {
+            let mut count = 0;
+            for info in repo.rev_walk([tip]).with_hidden([hidden]).all()? {
+                info?;
+                count += 1;
+            }
+            Ok(count)
+        };
+        let ahead = count_only_in(commit, other)?;
+        let behind = count_only_in(other, commit)?;
+
+        Ok(match (ahead, behind) {
+            (0, distance) => CommitRelationship::Ancestor { distance },
+            (distance, 0) => CommitRelationship::Descendant { distance },
+            (ahead, behind) => CommitRelationship::Diverged { ahead, behind },
+        })
+    }
+
+    /// Resolve a submodule url, which may be relative to the url of this repository
+    fn submodule_url(&self, url: &str) -> Result<GitUrl> {
+        let (url, _) = if url.starts_with("./") || url.starts_with("../") {
+            let mut base = self.url.clone();
+            base.set_path(&format!("{}/", base.path().trim_end_matches('/')));
+            GitUrl::parse_repository(base.join(url)?.as_str(), self.sources.protocol)?
+        } else {
+            GitUrl::parse_repository(url, self.sources.protocol)?
+        };
+
+        Ok(url)
+    }
+}
+
+/// Name the clone of `url` in the repositories directory
+///
+/// The host and the repository name keep it readable, the hash of the whole
+/// url keeps urls which only differ elsewhere, or by their punctuation, apart.
+fn cache_key(url: &Url) -> String {
+    let readable = [
+        url.host_str().unwrap_or_default(),
+        url.path_segments()
+            .and_then(|mut segments| segments.rfind(|segment| !segment.is_empty()))
+            .unwrap_or_default()
+            .trim_end_matches(".git"),
+    ]
+    .join("-")
+    .replace(
+        |c: char| !c.is_ascii_alphanumeric() && !matches!(c, '-' | '_' | '.'),
+        "_",
+    );
+    format!(
+        "{}-{:.16x}",
+        readable.trim_start_matches('.'),
+        Sha256::digest(url.as_str().as_bytes())
+    )
+}
+
+/// The commits pointed to by every branch and tag
+fn tips(repo: &gix::Repository) -> Result<Vec<ObjectId>> {
+    let mut tips = Vec::new();
+    for reference in repo.references()?.all()? {
+        let mut reference = reference.map_err(|err| anyhow!(err))?;
+        // References to anything but commits don't lead to any commit
+        if let Ok(tip) = reference.peel_to_commit() {
+            tips.push(tip.id);
+        }
+    }
+    Ok(tips)
+}
+
+/// Read the parts of a manifest declaring versions, if it can be parsed
+fn read_manifest(repo: &gix::Repository, id: ObjectId) -> Option<VersionManifest> {
+    let blob = repo.find_blob(id).ok()?;
+    toml::from_str(str::from_utf8(&blob.data).ok()?).ok()
+}
+
+/// Clone only the tip of the default branch of a git registry index into `dir`,
+/// unless it was cloned by a previous run
+pub fn clone_registry_index(url: &str, dir: &Path) -> Result<()> {
+    if dir.try_exists()? {
+        return Ok(());
+    }
+
+    clone(
+        url,
+        dir,
+        Tags::None,
+        Shallow::DepthAtRemote(NonZeroU32::MIN),
+        &[],
+    )
+    .with_context(|| format!("clone registry index from {url}"))
+}
+
+/// Read `path` from the commit `HEAD` points to in the repository in `dir`
+pub fn read_head_file(dir: &Path, path: &str) -> Result<Vec<u8>> {
+    let repo = open(dir)?;
+    let entry = repo
+        .head_commit()?
+        .tree()?
+        .lookup_entry_by_path(path)?
+        .with_context(|| format!("`{path}` not found"))?;
+    let data = entry.object()?.detach().data;
+    Ok(data)
+}
+
+/// Make a bare clone of `url` in `repo_dir`, with `files` written into it
+///
+/// Several checkouts, or runs, may clone the same repository at once, so the
+/// clone is made in a scratch directory and only moved in place once complete.
+fn clone(
+    url: &str,
+    repo_dir: &Path,
+    tags: Tags,
+    shallow: Shallow,
+    files: &[(&str, &str)],
+) -> Result<()> {
+    let mut clone_dir = repo_dir.as_os_str().to_owned();
+    clone_dir.push(format!(".{}.tmp", scratch_suffix()));
+    let clone_dir = PathBuf::from(clone_dir);
+
+    // Neither the configuration of the host nor its `insteadOf` rewrites apply,
+    // only ours, the same as for later fetches
+    let remote_url = url.to_owned();
+    let clone = gix::clone::PrepareFetch::new(
+        url,
+        &clone_dir,
+        gix::create::Kind::Bare,
+        gix::create::Options::default(),
+        open_options(),
+    )
+    .and_then(|clone| {
+        clone
+            .with_shallow(shallow)
+            .configure_remote(move |remote| {
+                Ok(remote
+                    .with_url_without_url_rewrite(remote_url.as_str())?
+                    .with_fetch_tags(tags))
+            })
+            .fetch_only(gix::progress::Discard, &AtomicBool::new(false))
+    })
+    .map_err(anyhow::Error::from)
+    .and_then(|_| {
+        for (file, contents) in files {
+            fs::write(clone_dir.join(file), contents)?;
+        }
+        Ok(())
+    });
+    if let Err(err) = clone {
+        let _ = fs::remove_dir_all(&clone_dir);
+        return Err(err);
+    }
+
+    if let Err(err) = fs::rename(&clone_dir, repo_dir) {
+        fs::remove_dir_all(&clone_dir)?;
+        if !repo_dir.try_exists()? {
+            return Err(err).context("move clone in place");
+        }
+    }
+    Ok(())
+}
+
+/// A suffix for scratch directories which is unique to this process and call
+fn scratch_suffix() -> String {
+    format!(
+        "{}-{}",
+        process::id(),
+        SCRATCH_COUNTER.fetch_add(1, atomic::Ordering::Relaxed)
+    )
+}
+
+fn open(path: &Path) -> Result<gix::Repository> {
+    Ok(gix::open_opts(path, open_options())?)
+}
+
+/// Ignore the git configuration of the host, only the repository's own applies
+fn open_options() -> gix::open::Options {
+    gix::open::Options::isolated().config_overrides(CONFIG_OVERRIDES)
 }
 
 impl GitRepositoryCheckout<'_> {
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Find the path, relative to the repository root, of the manifest declaring `name`
+    ///
+    /// The workspace is looked up from `path_in_vcs`, relative to the repository root.
+    pub fn manifest_path(
+        &self,
+        default_toolchain: &str,
+        name: &str,
+        version: &Version,
+        path_in_vcs: &Path,
+    ) -> Result<PathBuf> {
+        #[derive(Debug, Deserialize)]
+        struct CargoMetadata {
+            packages: Vec<CargoMetadataPackage>,
+        }
+
+        #[derive(Debug, Deserialize)]
+        struct CargoMetadataPackage {
+            name: String,
+            version: Version,
+            manifest_path: PathBuf,
+        }
+
+        let mut cmd = Command::new("cargo");
+        cmd.arg("metadata")
+            .arg("--no-deps")
+            .arg("--format-version")
+            .arg("1");
+        if self.repository.sources.offline {
+            cmd.arg("--offline");
+        }
+        let out = cmd
+            .current_dir(self.dir.join(path_in_vcs))
+            .env("RUSTUP_TOOLCHAIN", default_toolchain)
+            .output()
+            .context("cargo metadata")?;
+        ensure!(out.status.success(), "`cargo metadata` is successful");
+
+        let metadata = serde_json::from_slice::<CargoMetadata>(&out.stdout)
+            .context("couldn't parse `cargo metadata` output")?;
+        let manifest_path = metadata
+            .packages
+            .into_iter()
+            .find(|package| package.name == name && package.version == *version)
+            .map(|package| package.manifest_path)
+            .with_context(|| format!("{name} v{version} not found in the workspace"))?;
+
+        let checkout_dir = self
+            .dir
+            .canonicalize()
+            .context("canonicalize checkout path")?;
+        Ok(manifest_path
+            .strip_prefix(checkout_dir)
+            .context("manifest is outside of the repository")?
+            .to_owned())
+    }
+
+    /// Package `name` the way `cargo publish` would, from `path_in_vcs`
+    ///
+    /// `path_in_vcs` is relative to the repository root, and is where cargo
+    /// runs from, so that packages outside of the root workspace are found.
     pub fn crate_package(
         &self,
         default_toolchain: &str,
         name: &str,
         version: &Version,
+        path_in_vcs: &Path,
     ) -> Result<Package> {
-        let package_path = self
-            .repository
-            .repo_dir
-            .join("target")
-            .join("package")
-            .join(format!("{name}-{version}.crate"));
-
-        if !package_path.try_exists()? {
-            let out = Command::new("cargo")
-                .arg("publish")
-                .arg("--dry-run")
-                .arg("--no-verify")
-                .arg("--package")
-                .arg(name)
-                .current_dir(&self.repository.repo_dir)
-                .env("RUSTUP_TOOLCHAIN", default_toolchain)
-                .output()
-                .context("cargo package")?;
-            ensure!(out.status.success(), "`cargo package` is successful");
-            ensure!(
-                package_path.try_exists()?,
-                "`cargo package` generated a file"
-            );
+        let target_dir = self.dir.join("target");
+        let package_dir = target_dir.join("package");
+        let file_name = format!("{name}-{version}.crate");
+
+        let mut cmd = Command::new("cargo");
+        if self.repository.sources.offline {
+            // `cargo publish --dry-run` always queries the registry
+            cmd.arg("package").arg("--offline");
+        } else {
+            cmd.arg("publish").arg("--dry-run");
         }
+        cmd.arg("--no-verify")
+            .arg("--package")
+            .arg(name)
+            .arg("--target-dir")
+            .arg(&target_dir);
+        let out = cmd
+            .current_dir(self.dir.join(path_in_vcs))
+            .env("RUSTUP_TOOLCHAIN", default_toolchain)
+            .output()
+            .context("cargo package")?;
+        ensure!(out.status.success(), "`cargo package` is successful");
+
+        // Recent versions of `cargo publish --dry-run` package into `tmp-crate`
+        let package_path = [
+            package_dir.join(&file_name),
+            package_dir.join("tmp-crate").join(&file_name),
+        ]
+        .into_iter()
+        .find(|package_path| package_path.exists())
+        .context("`cargo package` generated a file")?;
 
         Ok(Package::new(package_path))
     }
 }
 
+impl Drop for GitRepositoryCheckout<'_> {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
 impl<'a> GitTags<'a> {
-    pub fn find_tag_for_version(&'a self, name: &str, version: Version) -> Option<&'a GitTag<'a>> {
+    /// Find the tags of `version`, in the order of the `patterns` matching them
+    ///
+    /// `{name}` and `{version}` in the patterns are replaced with `name` and
+    /// `version`. A pattern matches its exact tag, or otherwise a tag which
+    /// only differs by case or by `-` and `_`. Each tag is returned once,
+    /// along with the first pattern which matched it.
+    pub fn find_tags_for_version<'p>(
+        &'a self,
+        name: &str,
+        version: Version,
+        patterns: impl IntoIterator<Item = &'p str>,
+    ) -> Vec<(&'a GitTag<'a>, &'p str)> {
         let mut clean_version = version;
         clean_version.build = semver::BuildMetadata::EMPTY;
+        let clean_version = clean_version.to_string();
 
-        let possible_tags = [
-            // With package name prefix
-            format!("{name}-v{clean_version}"),
-            format!("{name}-{clean_version}"),
-            format!("{name}_v{clean_version}"),
-            format!("{name}_{clean_version}"),
-            format!("{name}/v{clean_version}"),
-            format!("{name}v/{clean_version}"),
-            format!("{name}/{clean_version}"),
-            format!("{name}@v{clean_version}"),
-            format!("{name}@{clean_version}"),
-            // Just the version
-            format!("v{clean_version}"),
-            clean_version.to_string(),
-            format!("v/{clean_version}"),
-        ];
-        possible_tags
-            .iter()
-            .find_map(|possible_tag| self.0.iter().find(|&tag| tag.tag == **possible_tag))
+        let mut found = Vec::<(&GitTag, &str)>::new();
+        for pattern in patterns {
+            let possible_tag = pattern
+                .replace("{name}", name)
+                .replace("{version}", &clean_version);
+            let normalized_possible_tag = config::normalize(&possible_tag);
+
+            let tag = self
+                .0
+                .iter()
+                .find(|&tag| tag.tag == possible_tag)
+                .or_else(|| {
+                    self.0
+                        .iter()
+                        .find(|&tag| config::normalize(&tag.tag) == normalized_possible_tag)
+                });
+            if let Some(tag) = tag {
+                if !found.iter().any(|(found_tag, _)| *found_tag == tag) {
+                    found.push((tag, pattern));
+                }
+            }
+        }
+        found
     }
 
     #[allow(dead_code)]
@@ -185,24 +892,17 @@ impl<'a> GitTags<'a> {
 }
 
 impl GitTag<'_> {
+    pub fn name(&self) -> &str {
+        &self.tag
+    }
+
     pub fn commit(&self) -> Result<String> {
-        let out = Command::new("git")
-            .arg("rev-list")
-            .arg("-n")
-            .arg("1")
-            .arg(&self.tag)
-            .current_dir(&self.repository.repo_dir)
-            .output()
+        let repo = self.repository.repo.to_thread_local();
+        let commit = repo
+            .find_reference(format!("refs/tags/{}", self.tag).as_str())?
+            .peel_to_commit()
             .context("find out commit behind tag")?;
-        ensure!(out.status.success(), "`git rev-list` is successful");
-
-        let commit = str::from_utf8(&out.stdout)
-            .context("git tag isn't utf-8")?
-            .lines()
-            .next()
-            .context("output is empty")?
-            .to_owned();
-        Ok(commit)
+        Ok(commit.id.to_string())
     }
 }
 
@@ -226,36 +926,185 @@ impl Ord for GitTag<'_> {
     }
 }
 
-impl TryFrom<Url> for GitUrl {
-    type Error = anyhow::Error;
+/// The forges whose web urls can be told apart from the repository url
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Forge {
+    GitHub,
+    /// GitLab, where projects may be nested into subgroups
+    GitLab,
+    /// Gitea, Forgejo and Codeberg
+    Gitea,
+    SourceHut,
+    Bitbucket,
+    Generic,
+}
+
+impl Forge {
+    fn from_host(host: &str) -> Self {
+        match host {
+            "github.com" | "www.github.com" => Self::GitHub,
+            "gitlab.com" => Self::GitLab,
+            "codeberg.org" => Self::Gitea,
+            "git.sr.ht" => Self::SourceHut,
+            "bitbucket.org" => Self::Bitbucket,
+            _ if host.starts_with("gitlab.") => Self::GitLab,
+            _ if host.starts_with("gitea.") || host.starts_with("forgejo.") => Self::Gitea,
+            _ => Self::Generic,
+        }
+    }
+
+    /// Split the path segments of a web url into those of the repository
+    /// and the directory it points at, if any
+    fn split_path<'a>(self, segments: &'a [&'a str]) -> Result<(Vec<&'a str>, &'a [&'a str])> {
+        let (repository, rest) = match self {
+            Self::GitHub | Self::Gitea | Self::SourceHut | Self::Bitbucket => {
+                ensure!(
+                    segments.len() >= 2,
+                    "repository is missing user/org or repo name"
+                );
+                segments.split_at(2)
+            }
+            Self::GitLab => {
+                // Project pages are `<group>/<subgroup>/<project>/-/tree/<ref>/<dir>`,
+                // older ones lack the `-`
+                let end = segments
+                    .iter()
+                    .position(|&segment| segment == "-")
+                    .or_else(|| {
+                        segments
+                            .iter()
+                            .skip(2)
+                            .position(|&segment| segment == "tree" || segment == "blob")
+                            .map(|position| position + 2)
+                    })
+                    .unwrap_or(segments.len());
+                ensure!(end >= 2, "repository is missing group or project name");
+                let (repository, rest) = segments.split_at(end);
+                (repository, rest.strip_prefix(&["-"]).unwrap_or(rest))
+            }
+            Self::Generic => (segments, &[][..]),
+        };
+
+        let dir = match (self, rest) {
+            (Self::GitHub | Self::GitLab, ["tree", _ref, dir @ ..])
+            | (Self::Gitea, ["src", "branch" | "tag" | "commit", _ref, dir @ ..])
+            | (Self::SourceHut, ["tree", _ref, "item", dir @ ..])
+            | (Self::Bitbucket, ["src", _ref, dir @ ..]) => dir,
+            // Links to a file, most likely the manifest, hint at its directory
+            (Self::GitHub | Self::GitLab, ["blob", _ref, dir @ .., _file]) => dir,
+            _ => &[],
+        };
+
+        let mut repository = repository.to_vec();
+        if self != Self::Generic {
+            if let Some(name) = repository.last_mut() {
+                *name = name.trim_end_matches(".git");
+            }
+        }
+        Ok((repository, dir))
+    }
+}
 
-    fn try_from(url: Url) -> Result<Self, Self::Error> {
-        ensure!(
-            matches!(url.scheme(), "http" | "https"),
-            "Bad repository scheme"
-        );
+impl GitUrl {
+    /// Parse the `repository` field of a manifest
+    ///
+    /// Besides http urls, `git+https://`, `git://`, `ssh://` and scp-like
+    /// `git@host:owner/repo` urls are accepted. The latter ones are turned into
+    /// https urls unless `protocol` preserves them.
+    ///
+    /// Web urls pointing inside a repository on a known forge, such as
+    /// `https://github.com/owner/repo/tree/main/crates/foo`, are reduced to the
+    /// url of the repository. The directory they point at is returned as a
+    /// hint of where the package lives.
+    pub fn parse_repository(
+        repository: &str,
+        protocol: RepositoryProtocol,
+    ) -> Result<(Self, Option<PathBuf>)> {
+        let repository = repository.trim();
+        let repository = repository.strip_prefix("git+").unwrap_or(repository);
+        let url = match scp_like_url(repository) {
+            Some(url) => url,
+            None => repository
+                .parse::<Url>()
+                .context("repository isn't a valid url")?,
+        };
+        let mut url = match (url.scheme(), protocol) {
+            ("http" | "https", _) | ("ssh" | "git", RepositoryProtocol::Preserve) => url,
+            ("ssh" | "git", RepositoryProtocol::Https) => {
+                let host = url.host_str().context("repository doesn't have a `host`")?;
+                Url::parse(&format!("https://{host}{}", url.path()))
+                    .context("rewrite repository url to https")?
+            }
+            (scheme, _) => bail!("Bad repository scheme `{scheme}`"),
+        };
         let host = url
-            .host()
+            .host_str()
             .context("repository doesn't have a `host`")?
-            .to_string();
-
-        Ok(Self(
-            if host == "github.com" || host.starts_with("gitlab.") {
-                let mut url = url;
-                let mut path = url.path().strip_prefix('/').unwrap().split('/');
-                url.set_path(&format!(
-                    "/{}/{}.git",
-                    path.next().context("repository is missing user/org")?,
-                    path.next()
-                        .context("repository is missing repo name")?
-                        .trim_end_matches(".git")
-                ));
-                url
-            } else {
-                url
-            },
-        ))
+            .to_owned();
+        let forge = Forge::from_host(&host);
+
+        let segments = url
+            .path_segments()
+            .into_iter()
+            .flatten()
+            .filter(|segment| !segment.is_empty())
+            .collect::<Vec<_>>();
+        let (repository, dir) = forge.split_path(&segments)?;
+
+        let mut path = format!("/{}", repository.join("/"));
+        if !matches!(forge, Forge::SourceHut | Forge::Generic) {
+            path.push_str(".git");
+        }
+        let dir = (!dir.is_empty()).then(|| dir.iter().collect::<PathBuf>());
+
+        url.set_path(&path);
+        url.set_query(None);
+        url.set_fragment(None);
+        Ok((Self(url), dir))
+    }
+
+    /// The name of the clone of this repository in the repositories directory
+    pub fn cache_key(&self) -> String {
+        cache_key(&self.0)
+    }
+}
+
+impl UrlRewrites {
+    /// `rewrites` are pairs of the prefix to replace and its replacement
+    pub fn new(rewrites: impl IntoIterator<Item = (String, String)>) -> Self {
+        Self(rewrites.into_iter().collect())
+    }
+
+    /// Replace the longest matching prefix of `url`, if any
+    pub fn rewrite(&self, url: &str) -> String {
+        self.0
+            .iter()
+            .filter(|(prefix, _)| url.starts_with(prefix.as_str()))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map_or_else(
+                || url.to_owned(),
+                |(prefix, replacement)| format!("{replacement}{}", &url[prefix.len()..]),
+            )
+    }
+}
+
+/// Turn an scp-like `[user@]host:path` into an `ssh://` url
+fn scp_like_url(repository: &str) -> Option<Url> {
+    if repository.contains("://") {
+        return None;
+    }
+
+    let (user_host, path) = repository.split_once(':')?;
+    // A single letter is a Windows drive rather than a host
+    if user_host.len() < 2 || user_host.contains('/') || path.is_empty() {
+        return None;
     }
+
+    Url::parse(&format!(
+        "ssh://{user_host}/{}",
+        path.trim_start_matches('/')
+    ))
+    .ok()
 }
 
 impl Display for GitUrl {
@@ -263,3 +1112,164 @@ impl Display for GitUrl {
         Display::fmt(&self.0, f)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_repository() {
+        let cases = [
+            (
+                "https://github.com/owner/repo",
+                "https://github.com/owner/repo.git",
+                None,
+            ),
+            (
+                "https://github.com/owner/repo.git",
+                "https://github.com/owner/repo.git",
+                None,
+            ),
+            (
+                "https://github.com/owner/repo/",
+                "https://github.com/owner/repo.git",
+                None,
+            ),
+            (
+                "https://www.github.com/owner/repo",
+                "https://www.github.com/owner/repo.git",
+                None,
+            ),
+            (
+                "https://github.com/owner/repo/tree/main/crates/foo",
+                "https://github.com/owner/repo.git",
+                Some("crates/foo"),
+            ),
+            (
+                "https://github.com/owner/repo/blob/main/crates/foo/Cargo.toml",
+                "https://github.com/owner/repo.git",
+                Some("crates/foo"),
+            ),
+            (
+                "https://github.com/owner/repo#readme",
+                "https://github.com/owner/repo.git",
+                None,
+            ),
+            (
+                "git+https://github.com/owner/repo",
+                "https://github.com/owner/repo.git",
+                None,
+            ),
+            (
+                "git://github.com/owner/repo",
+                "https://github.com/owner/repo.git",
+                None,
+            ),
+            (
+                "ssh://git@github.com/owner/repo.git",
+                "https://github.com/owner/repo.git",
+                None,
+            ),
+            (
+                "git@github.com:owner/repo.git",
+                "https://github.com/owner/repo.git",
+                None,
+            ),
+            (
+                "https://gitlab.com/group/subgroup/project",
+                "https://gitlab.com/group/subgroup/project.git",
+                None,
+            ),
+            (
+                "https://gitlab.com/group/subgroup/project/-/tree/main/foo",
+                "https://gitlab.com/group/subgroup/project.git",
+                Some("foo"),
+            ),
+            (
+                "https://gitlab.com/group/project/tree/main/foo",
+                "https://gitlab.com/group/project.git",
+                Some("foo"),
+            ),
+            (
+                "https://gitlab.example.org/group/tree/-/tree/main/foo",
+                "https://gitlab.example.org/group/tree.git",
+                Some("foo"),
+            ),
+            (
+                "https://codeberg.org/owner/repo/src/branch/main/foo",
+                "https://codeberg.org/owner/repo.git",
+                Some("foo"),
+            ),
+            (
+                "https://gitea.example.org/owner/repo/src/tag/v1.0.0/foo/bar",
+                "https://gitea.example.org/owner/repo.git",
+                Some("foo/bar"),
+            ),
+            (
+                "https://git.sr.ht/~owner/repo",
+                "https://git.sr.ht/~owner/repo",
+                None,
+            ),
+            (
+                "https://git.sr.ht/~owner/repo/tree/main/item/foo",
+                "https://git.sr.ht/~owner/repo",
+                Some("foo"),
+            ),
+            (
+                "https://bitbucket.org/owner/repo/src/main/foo",
+                "https://bitbucket.org/owner/repo.git",
+                Some("foo"),
+            ),
+            (
+                "https://example.org/some/path/repo.git",
+                "https://example.org/some/path/repo.git",
+                None,
+            ),
+        ];
+        for (repository, url, dir) in cases {
+            let (parsed_url, parsed_dir) =
+                GitUrl::parse_repository(repository, RepositoryProtocol::Https)
+                    .unwrap_or_else(|err| panic!("{repository}: {err:#}"));
+            assert_eq!(parsed_url.to_string(), url, "{repository}");
+            assert_eq!(parsed_dir, dir.map(PathBuf::from), "{repository}");
+        }
+    }
+
+    #[test]
+    fn parse_repository_preserving_protocol() {
+        let cases = [
+            (
+                "git@github.com:owner/repo.git",
+                "ssh://git@github.com/owner/repo.git",
+            ),
+            (
+                "ssh://git@example.org/repo.git",
+                "ssh://git@example.org/repo.git",
+            ),
+            ("git://example.org/repo.git", "git://example.org/repo.git"),
+        ];
+        for (repository, url) in cases {
+            let (parsed_url, _) =
+                GitUrl::parse_repository(repository, RepositoryProtocol::Preserve)
+                    .unwrap_or_else(|err| panic!("{repository}: {err:#}"));
+            assert_eq!(parsed_url.to_string(), url, "{repository}");
+        }
+    }
+
+    #[test]
+    fn parse_invalid_repository() {
+        for repository in [
+            "",
+            "not a url",
+            "ftp://example.org/repo.git",
+            "https://github.com/owner",
+            "https://gitlab.com/project",
+            "C:/path/to/repo",
+        ] {
+            assert!(
+                GitUrl::parse_repository(repository, RepositoryProtocol::Https).is_err(),
+                "{repository}"
+            );
+        }
+    }
+}
@@ -1,30 +1,52 @@
 use std::{
-    collections::BTreeMap,
+    collections::{BTreeMap, BTreeSet},
     env, fs,
-    io::{self as std_io, Read},
-    path::Path,
+    io::{self as std_io, Read, Write as _},
+    path::{Component, Path, PathBuf},
+    process::ExitCode,
     str,
 };
 
-use anyhow::{ensure, Context, Result};
-use cargo_lock::{package::SourceKind, Checksum, Lockfile};
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use cargo_lock::{package::SourceKind, Checksum, Lockfile, SourceId};
 use cargo_toml::Manifest;
+use clap::Parser as _;
 use git::GitUrl;
-use rayon::iter::{IntoParallelIterator, ParallelIterator};
+use rayon::iter::{Either, IntoParallelIterator, ParallelIterator};
 use serde::Deserialize;
 use sha2::{Digest as _, Sha256};
-use url::Url;
 
-use crate::package::{PackageComparison, PackageContents};
+use crate::cli::{CacheCommand, Cli, Command, GogglesArgs, OutputFormat};
+use crate::diff::FileDiff;
+use crate::manifest::ManifestDifference;
+use crate::package::{Package, PackageComparison, PackageContents};
+use crate::policy::Policy;
+use crate::report::{PackageReport, Report};
+use crate::verdict::FindingKind;
 
-use self::git::GitRepository;
-use self::registry::RegistryCrate;
+use self::cache::{Cache, CacheEntryKind, CacheUsage};
+use self::cargo_config::CargoConfig;
+use self::config::Config;
+use self::git::{
+    CommitRelationship, CommitStatus, GitRepository, GitRepositoryCheckout, GitRepositorySources,
+    RepositoryProtocol,
+};
+use self::registry::{CrateSources, Registry, RegistryCrate};
 
+mod cache;
+mod cargo_config;
+mod cli;
+mod config;
+mod diff;
 mod git;
 mod io;
+mod manifest;
 mod package;
+mod policy;
 mod registry;
+mod report;
 mod rustup;
+mod verdict;
 
 const USER_AGENT: &str = concat!(
     env!("CARGO_PKG_NAME"),
@@ -35,17 +57,19 @@ const USER_AGENT: &str = concat!(
     ")"
 );
 
-const CRATES_IO_INDEX: &str = "https://github.com/rust-lang/crates.io-index";
-
 #[derive(Debug, Deserialize)]
 struct CargoVcsInfo {
     git: CargoGitVcsInfo,
-    // path_in_vcs: Option<String>,
+    /// Directory of the package relative to the repository root, not recorded by older cargo versions
+    path_in_vcs: Option<PathBuf>,
 }
 
 #[derive(Debug, Deserialize)]
 struct CargoGitVcsInfo {
     sha1: String,
+    /// Whether the package was published from a working tree with uncommitted changes
+    #[serde(default)]
+    dirty: bool,
 }
 
 #[derive(Debug)]
@@ -53,10 +77,192 @@ struct ResolvedPackage {
     lock_info: cargo_lock::Package,
     registry_crate: RegistryCrate,
     repository_url: GitUrl,
+    /// Directory of the package suggested by the `repository` url
+    subdirectory_hint: Option<PathBuf>,
     cargo_vcs_info: Option<CargoVcsInfo>,
+    manifest: Manifest,
+}
+
+/// Where the package lives inside the repository
+#[derive(Debug, Copy, Clone)]
+enum PackageDir<'a> {
+    /// Recorded by `path_in_vcs`, the package must be found there
+    Recorded(&'a Path),
+    /// Suggested by the `repository` url, used only if the package is found there
+    Hinted(&'a Path),
+    Unknown,
+}
+
+impl PackageDir<'_> {
+    /// The directory to package from, relative to `checkout_dir`
+    ///
+    /// An empty path lets cargo search the workspace for the package.
+    fn resolve(&self, checkout_dir: &Path, lock_info: &cargo_lock::Package) -> Result<&Path> {
+        match *self {
+            Self::Recorded(dir) => {
+                declares_package(checkout_dir, dir, lock_info)
+                    .context(FindingKind::PathInVcsMismatch)?;
+                Ok(dir)
+            }
+            Self::Hinted(dir) if declares_package(checkout_dir, dir, lock_info).is_ok() => Ok(dir),
+            Self::Hinted(_) | Self::Unknown => Ok(Path::new("")),
+        }
+    }
+}
+
+/// How many commits declaring the released version get packaged at most when
+/// looking for the released commit
+const MAX_HEURISTIC_CANDIDATES: usize = 10;
+
+/// Exit status when at least one finding was denied by the `--deny` policy
+const EXIT_DENIED: u8 = 1;
+/// Exit status when the run itself couldn't be completed
+const EXIT_ERROR: u8 = 2;
+
+fn main() -> ExitCode {
+    let Cli::Goggles(args) = Cli::parse();
+
+    let result = match &args.command {
+        Some(Command::Cache { command }) => run_cache(command, &args).map(|()| ExitCode::SUCCESS),
+        _ => run(args).map(|report| {
+            if report.denied_findings > 0 {
+                ExitCode::from(EXIT_DENIED)
+            } else {
+                ExitCode::SUCCESS
+            }
+        }),
+    };
+    result.unwrap_or_else(|err| {
+        eprintln!("Error: {err:?}");
+        ExitCode::from(EXIT_ERROR)
+    })
+}
+
+/// Inspect or clean up the cache
+fn run_cache(command: &CacheCommand, args: &GogglesArgs) -> Result<()> {
+    let cache = Cache::open(args.cache_dir.clone()).context("open cache")?;
+    let mut stdout = std_io::stdout().lock();
+
+    match command {
+        CacheCommand::List => {
+            let entries = cache.entries().context("list cache entries")?;
+            match args.format {
+                OutputFormat::Human => cache::write_list(&entries, &mut stdout)?,
+                OutputFormat::Json => serde_json::to_writer_pretty(&mut stdout, &entries)?,
+            }
+        }
+        CacheCommand::Size => {
+            let entries = cache.entries().context("list cache entries")?;
+            let size = cache.size(&entries).context("measure cache size")?;
+            match args.format {
+                OutputFormat::Human => size.write_human(&mut stdout)?,
+                OutputFormat::Json => serde_json::to_writer_pretty(&mut stdout, &size)?,
+            }
+        }
+        CacheCommand::Prune {
+            older_than,
+            unused_by,
+        } => {
+            let used = unused_by
+                .as_deref()
+                .map(|lock| cache_usage(&cache, args, lock))
+                .transpose()?;
+            let removed = cache
+                .prune(*older_than, used.as_ref())
+                .context("prune cache")?;
+            match args.format {
+                OutputFormat::Human => {
+                    cache::write_list(&removed, &mut stdout)?;
+                    writeln!(
+                        stdout,
+                        "Removed {} entries, freeing {}",
+                        removed.len(),
+                        cache::format_size(removed.iter().map(|entry| entry.size).sum())
+                    )?;
+                }
+                OutputFormat::Json => serde_json::to_writer_pretty(&mut stdout, &removed)?,
+            }
+        }
+        CacheCommand::Clean => {
+            let freed = cache.clean().context("clean cache")?;
+            match args.format {
+                OutputFormat::Human => {
+                    writeln!(stdout, "Freed {}", cache::format_size(freed))?;
+                }
+                OutputFormat::Json => {
+                    serde_json::to_writer_pretty(
+                        &mut stdout,
+                        &serde_json::json!({ "freed": freed }),
+                    )?;
+                }
+            }
+        }
+    }
+
+    if args.format == OutputFormat::Json {
+        writeln!(stdout)?;
+    }
+    Ok(())
 }
 
-fn main() -> Result<()> {
+/// Find out which cached crates and repositories are needed to analyze `lock`
+///
+/// Repositories are identified by the manifests of the crates, found in the
+/// cache or in cargo's own cache. Those of crates which are in neither can't
+/// be identified, nor can the clones of submodules.
+fn cache_usage(cache: &Cache, args: &GogglesArgs, lock: &Path) -> Result<CacheUsage> {
+    let lock = Lockfile::load(lock).with_context(|| format!("decode {}", lock.display()))?;
+    let current_dir = env::current_dir()?;
+    let config =
+        Config::load(args.config.as_deref(), &current_dir).context("load configuration")?;
+    let cargo_config = CargoConfig::load(&current_dir).context("load cargo configuration")?;
+
+    let crate_files = cache
+        .entries()
+        .context("list cache entries")?
+        .into_iter()
+        .filter(|entry| entry.kind == CacheEntryKind::Crate)
+        .map(|entry| (entry.name, entry.path))
+        .collect::<Vec<_>>();
+
+    let mut used = CacheUsage::default();
+    for lock_info in &lock.packages {
+        let Some(source) = lock_info
+            .source
+            .as_ref()
+            .filter(|source| source.is_remote_registry())
+        else {
+            continue;
+        };
+        let name = format!("{}@{}", lock_info.name, lock_info.version);
+
+        let cached = crate_files
+            .iter()
+            .filter(|(crate_name, _)| *crate_name == name)
+            .map(|(_, path)| path.clone());
+        let cargo_cached = registry::cargo_cache_dirs(cargo_config.cargo_home(), source)?
+            .into_iter()
+            .map(|dir| dir.join(format!("{}-{}.crate", lock_info.name, lock_info.version)))
+            .filter(|path| path.is_file());
+        let repository = cached.chain(cargo_cached).find_map(|path| {
+            let (_, manifest) = read_package_metadata(&Package::new(path)).ok()?;
+            let repository = manifest.package?.repository?.get().ok()?.clone();
+            let (repository_url, _) =
+                GitUrl::parse_repository(&repository, config.repository_protocol()).ok()?;
+            Some(repository_url)
+        });
+
+        used.crates.insert(name);
+        used.repositories
+            .extend(repository.map(|url| url.cache_key()));
+    }
+
+    Ok(used)
+}
+
+fn run(args: GogglesArgs) -> Result<Report> {
+    let policy = Policy::new(args.deny, args.warn);
+
     let http_client = reqwest::blocking::Client::builder()
         .user_agent(USER_AGENT)
         .build()?;
@@ -64,11 +270,9 @@ fn main() -> Result<()> {
     let default_toolchain = self::rustup::default_toolchain();
 
     let current_dir = env::current_dir()?;
-    let temp_dir = env::temp_dir().join(env!("CARGO_PKG_NAME"));
-    let crates_dir = temp_dir.join("crates");
-    let repos_dir = temp_dir.join("repositories");
-    fs::create_dir_all(&crates_dir)?;
-    fs::create_dir_all(&repos_dir)?;
+    let cache = Cache::open(args.cache_dir).context("open cache")?;
+    let crates_dir = cache.crates_dir();
+    let repos_dir = cache.repositories_dir();
 
     let lock = current_dir.join("Cargo.lock");
     ensure!(
@@ -78,87 +282,184 @@ fn main() -> Result<()> {
 
     let lock = Lockfile::load(lock).context("decode Cargo.lock")?;
 
-    let resolved_packages = lock
-        .packages
+    let (packages, show_diff) = match &args.command {
+        Some(Command::Diff { package }) => {
+            let packages = lock
+                .packages
+                .into_iter()
+                .filter(|lock_info| package.matches(lock_info))
+                .collect::<Vec<_>>();
+            ensure!(
+                !packages.is_empty(),
+                "package `{}` not found in Cargo.lock",
+                package.name
+            );
+            (packages, true)
+        }
+        Some(Command::Cache { .. }) => unreachable!("handled by `run_cache`"),
+        None => (lock.packages, args.show_diff),
+    };
+
+    let crate_sources = CrateSources {
+        crates_dirs: args.crates_dir,
+        vendor_dir: args.vendor_dir,
+        offline: args.offline,
+    };
+    let config =
+        Config::load(args.config.as_deref(), &current_dir).context("load configuration")?;
+    let repository_sources = GitRepositorySources {
+        mirror_dir: args.repositories_dir,
+        offline: args.offline,
+        fetch_interval: (!args.offline && !args.no_fetch).then_some(args.fetch_interval),
+        protocol: config.repository_protocol(),
+        rewrites: config.url_rewrites(),
+    };
+    let cargo_config = CargoConfig::load(&current_dir).context("load cargo configuration")?;
+    let registries = packages
+        .iter()
+        .filter_map(|lock_info| lock_info.source.as_ref())
+        .filter(|source| source.is_remote_registry())
+        .collect::<BTreeSet<_>>()
         .into_par_iter()
-        .filter_map(|lock_info| {
-            let name = lock_info.name.clone();
-            let version = lock_info.version.clone();
+        .map(|source| {
+            let registry = Registry::load(
+                &http_client,
+                &crates_dir,
+                &cargo_config,
+                &crate_sources,
+                source,
+            )
+            .with_context(|| format!("couldn't load registry `{source}`"));
+            (source.clone(), registry)
+        })
+        .collect::<BTreeMap<_, _>>();
 
-            match resolve_package(&http_client, &crates_dir, lock_info) {
-                Ok(resolved_package) => Some(resolved_package),
+    let (resolved_packages, mut package_reports) = packages
+        .into_par_iter()
+        .map(|lock_info| {
+            let mut report = PackageReport::new(&lock_info);
+
+            match resolve_package(
+                &http_client,
+                &registries,
+                repository_sources.protocol,
+                lock_info,
+                &mut report,
+            ) {
+                Ok(resolved_package) => Either::Left((resolved_package, report)),
                 Err(err) => {
-                    println!("Couldn't resolve package {name} v{version} err={err:?}");
-                    None
+                    report.error(&err);
+                    Either::Right(report)
                 }
             }
         })
-        .collect::<Vec<_>>();
+        .partition_map::<Vec<_>, Vec<_>, _, _, _>(|either| either);
 
     let mut grouped_resolved_packages = BTreeMap::<_, Vec<_>>::new();
-    for resolved_package in resolved_packages {
+    for (resolved_package, report) in resolved_packages {
         grouped_resolved_packages
             .entry(resolved_package.repository_url.clone())
             .or_default()
-            .push(resolved_package);
+            .push((resolved_package, report));
     }
 
-    grouped_resolved_packages
+    let analyzed_package_reports = grouped_resolved_packages
         .into_par_iter()
-        .for_each(|(repository_url, resolved_packages)| {
-            let mut git_repository = match GitRepository::obtain(&repos_dir, repository_url) {
-                Ok(git_repository) => git_repository,
-                Err(err) => {
-                    println!(
-                        "Couldn't obtain git repository for {} v{} err={:?} url={}",
-                        resolved_packages[0].lock_info.name,
-                        resolved_packages[0].lock_info.version,
-                        err,
-                        resolved_packages[0].repository_url
-                    );
-                    return;
-                }
+        .flat_map(|(repository_url, resolved_packages)| {
+            let git_repository =
+                match GitRepository::obtain(&repos_dir, &repository_sources, repository_url)
+                    .map_err(|err| FindingKind::CloneFailed.or_context(err))
+                {
+                    Ok(git_repository) => git_repository,
+                    Err(err) => {
+                        return resolved_packages
+                            .into_iter()
+                            .map(|(_, mut report)| {
+                                report.error(&err);
+                                report
+                            })
+                            .collect::<Vec<_>>();
+                    }
+                };
+
+            let fetch_result = match repository_sources.fetch_interval {
+                Some(fetch_interval) => git_repository.fetch(fetch_interval),
+                None => Ok(()),
             };
 
-            for resolved_package in resolved_packages {
-                if let Err(err) =
-                    analyze_package(&default_toolchain, &resolved_package, &mut git_repository)
-                {
-                    println!(
-                        "Couldn't analyze package for {} v{} err={:?} url={}",
-                        resolved_package.lock_info.name,
-                        resolved_package.lock_info.version,
-                        err,
-                        resolved_package.repository_url
-                    );
-                }
-            }
-        });
+            // Every analysis gets its own checkout, so they can run concurrently
+            resolved_packages
+                .into_par_iter()
+                .map(|(resolved_package, mut report)| {
+                    if let Err(err) = &fetch_result {
+                        report.finding(
+                            FindingKind::FetchFailed,
+                            format!(
+                                "Couldn't fetch the repository of {}, its tags may be out of date: {err:#}",
+                                resolved_package.lock_info.name
+                            ),
+                        );
+                    }
 
-    Ok(())
+                    if let Err(err) = analyze_package(
+                        &default_toolchain,
+                        &config,
+                        show_diff,
+                        &resolved_package,
+                        &git_repository,
+                        &mut report,
+                    ) {
+                        report.error(&err);
+                    }
+
+                    report
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+    package_reports.extend(analyzed_package_reports);
+
+    let report = Report::new(package_reports, &policy);
+    let stdout = std_io::stdout().lock();
+    match (args.format, &args.command) {
+        (OutputFormat::Human, Some(Command::Diff { .. })) => report.write_diffs(stdout)?,
+        (OutputFormat::Human, _) => report.write_human(stdout)?,
+        (OutputFormat::Json, _) => report.write_json(stdout)?,
+    }
+
+    Ok(report)
 }
 
 fn resolve_package(
     http_client: &reqwest::blocking::Client,
-    cache_dir: &Path,
+    registries: &BTreeMap<SourceId, Result<Registry>>,
+    repository_protocol: RepositoryProtocol,
     lock_info: cargo_lock::Package,
+    report: &mut PackageReport,
 ) -> Result<ResolvedPackage> {
     //
-    // Check that it's the official crates.io registry
+    // Check that it comes from a registry
     //
 
     let source = lock_info
         .source
         .as_ref()
-        .context("package doesn't have a `source`")?;
-    ensure!(
-        source.kind() == &SourceKind::Registry,
-        "package source isn't Registry"
-    );
-    ensure!(
-        source.url().as_str() == CRATES_IO_INDEX,
-        "package is part of the official crates.io registry"
-    );
+        .context("package doesn't have a `source`, it is most likely a workspace member")
+        .context(FindingKind::WorkspaceMember)?;
+    let registry = match registries.get(source) {
+        Some(Ok(registry)) => registry,
+        Some(Err(err)) => return Err(anyhow!("{err:#}").context(FindingKind::DownloadFailed)),
+        None => {
+            let reason = match source.kind() {
+                SourceKind::Git(_) => "git dependencies aren't supported",
+                SourceKind::Path => "path dependencies aren't supported",
+                SourceKind::LocalRegistry => "local registries aren't supported",
+                SourceKind::Directory => "directory sources aren't supported",
+                _ => "package source isn't supported",
+            };
+            return Err(anyhow!("{reason}: `{source}`").context(FindingKind::UnsupportedSource));
+        }
+    };
 
     //
     // Download the package
@@ -166,11 +467,12 @@ fn resolve_package(
 
     let registry_crate = RegistryCrate::obtain(
         http_client,
-        cache_dir,
+        registry,
         lock_info.name.as_str(),
         &lock_info.version,
+        lock_info.checksum.as_ref(),
     )
-    .context("couldn't obtain package")?;
+    .map_err(|err| FindingKind::DownloadFailed.or_context(err))?;
     let registry_crate_package = registry_crate.package();
 
     //
@@ -178,19 +480,24 @@ fn resolve_package(
     //
 
     match lock_info.checksum {
+        // Already verified against `.cargo-checksum.json`
+        Some(Checksum::Sha256(_)) if registry_crate.is_vendored() => {}
         Some(Checksum::Sha256(expected_sha256_hash)) => {
             let mut sha256 = Sha256::new();
-            std_io::copy(&mut registry_crate_package.raw_reader()?, &mut sha256)?;
+            std_io::copy(&mut registry_crate_package.raw_reader()?, &mut sha256)
+                .context(FindingKind::InvalidCrate)?;
             let sha256 = sha256.finalize();
 
-            ensure!(
-                <[u8; 32]>::from(sha256) == expected_sha256_hash,
-                "package {} digest doesn't match",
-                lock_info.name
-            );
+            if <[u8; 32]>::from(sha256) != expected_sha256_hash {
+                return Err(anyhow!("package {} digest doesn't match", lock_info.name)
+                    .context(FindingKind::ChecksumMismatch));
+            }
         }
         None => {
-            println!("package {} doesn't have a checksum", lock_info.name);
+            report.finding(
+                FindingKind::MissingChecksum,
+                format!("package {} doesn't have a checksum", lock_info.name),
+            );
         }
     }
 
@@ -198,185 +505,743 @@ fn resolve_package(
     // Read `.cargo_vcs_info.json` and `Cargo.toml`
     //
 
-    let mut cargo_vcs_info = None;
-    let mut cargo_toml = None;
-
-    let mut tar = registry_crate_package.archive_reader()?;
-    for entry in tar.entries()? {
-        let mut entry = entry?;
-        let path = entry
-            .path()?
-            .to_str()
-            .context("entry path isn't utf-8")?
-            .to_owned();
-
-        // TODO: verify that the `.tar` doesn't contain multiple directories
-
-        if path.ends_with(".cargo_vcs_info.json") {
-            ensure!(
-                cargo_vcs_info.is_none(),
-                "`.cargo_vcs_info.json` encountered multiple times"
+    let (cargo_vcs_info, manifest) =
+        read_package_metadata(&registry_crate_package).context(FindingKind::InvalidCrate)?;
+    let cargo_vcs_info = match cargo_vcs_info {
+        Some(Ok(cargo_vcs_info)) => Some(cargo_vcs_info),
+        Some(Err(err)) => {
+            report.finding(
+                FindingKind::MalformedVcsInfo,
+                format!(
+                    "Package {} v{} has a `.cargo_vcs_info.json` which can't be parsed: {err:#}",
+                    lock_info.name, lock_info.version
+                ),
             );
-
-            cargo_vcs_info = serde_json::from_reader::<_, CargoVcsInfo>(&mut entry).ok();
-        } else if path.ends_with("Cargo.toml") {
-            if cargo_toml.is_some() {
-                println!("`Cargo.toml` encountered multiple times");
-            }
-
-            let mut manifest = String::new();
-            entry.read_to_string(&mut manifest)?;
-            cargo_toml = Some(Manifest::from_str(&manifest)?);
+            None
         }
+        None => {
+            report.finding(
+                FindingKind::MissingVcsInfo,
+                format!(
+                    "Package {} v{} doesn't have a `.cargo_vcs_info.json`, it was likely published from outside of a git repository or by an old version of cargo",
+                    lock_info.name, lock_info.version
+                ),
+            );
+            None
+        }
+    };
+    report.vcs_info_sha1 = cargo_vcs_info
+        .as_ref()
+        .map(|cargo_vcs_info| cargo_vcs_info.git.sha1.clone());
+    report.vcs_info_dirty = cargo_vcs_info
+        .as_ref()
+        .map(|cargo_vcs_info| cargo_vcs_info.git.dirty);
+    if report.vcs_info_dirty == Some(true) {
+        report.finding(
+            FindingKind::DirtyVcsInfo,
+            format!(
+                "Package {} v{} was published from a working tree with uncommitted changes, its contents may not come from any commit",
+                lock_info.name, lock_info.version
+            ),
+        );
     }
 
-    let manifest = cargo_toml.context("`Cargo.toml` not found")?;
     let repository = manifest
         .package
         .as_ref()
-        .context("Package metadata missing")?
+        .context("Package metadata missing")
+        .context(FindingKind::InvalidCrate)?
         .repository
         .as_ref()
-        .context("missing `repository` attribute in Cargo.toml")?;
+        .context("missing `repository` attribute in Cargo.toml")
+        .context(FindingKind::NoRepository)?;
 
     //
     // Clone repository
     //
 
-    let repository_url = repository
-        .get()?
-        .parse::<Url>()
-        .context("repository isn't a valid url")?
-        .try_into()
-        .context("repository url isn't valid")?;
+    let repository = repository.get().context(FindingKind::InvalidCrate)?;
+    report.repository = Some(repository.clone());
+    let (repository_url, subdirectory_hint) =
+        GitUrl::parse_repository(repository, repository_protocol)
+            .context(FindingKind::InvalidRepositoryUrl)?;
+    report.repository_url = Some(repository_url.to_string());
 
     Ok(ResolvedPackage {
         lock_info,
         registry_crate,
         repository_url,
+        subdirectory_hint,
         cargo_vcs_info,
+        manifest,
     })
 }
 
+/// Search the history for the commit which best reproduces the registry package
+///
+/// Only commits at which the manifest declares the released version are
+/// considered, the first [`MAX_HEURISTIC_CANDIDATES`] of them are packaged
+/// and the one with the most files matching the registry package wins.
+fn locate_commit(
+    default_toolchain: &str,
+    git_repository: &GitRepository,
+    registry_crate: &RegistryCrate,
+    registry_package_contents: &PackageContents,
+    lock_info: &cargo_lock::Package,
+    package_dir: PackageDir<'_>,
+) -> Result<String> {
+    let runs = git_repository
+        .find_version_commits(lock_info.name.as_str(), &lock_info.version)
+        .context("search history for the released version")?;
+    // The released commit is usually at one end of a run, depending on whether
+    // the version is bumped right before the release or right after the previous one
+    let ends = runs.iter().flat_map(|run| {
+        [run.last(), run.first().filter(|_| run.len() > 1)]
+            .into_iter()
+            .flatten()
+    });
+    let middles = runs
+        .iter()
+        .flat_map(|run| run.iter().skip(1).take(run.len().saturating_sub(2)));
+    let candidates = ends.chain(middles).collect::<Vec<_>>();
+    let &first_candidate = candidates.first().with_context(|| {
+        format!(
+            "no commit declares {} v{} in its manifest",
+            lock_info.name, lock_info.version
+        )
+    })?;
+
+    let mut best = None;
+    for &candidate in candidates.iter().take(MAX_HEURISTIC_CANDIDATES) {
+        let Ok((_, _, contents)) = package_commit(
+            default_toolchain,
+            git_repository,
+            lock_info,
+            package_dir,
+            candidate,
+        ) else {
+            continue;
+        };
+
+        let comparisons =
+            PackageContents::compare(&contents, registry_package_contents).collect::<Vec<_>>();
+        let matching = comparisons
+            .iter()
+            .filter(|comparison| is_equivalent(registry_crate, comparison))
+            .count();
+        if best.is_none_or(|(_, best_matching)| matching > best_matching) {
+            best = Some((candidate, matching));
+        }
+        if matching == comparisons.len() {
+            break;
+        }
+    }
+
+    // Let the analysis report why none of them could be packaged
+    Ok(best
+        .map_or(first_candidate, |(candidate, _)| candidate)
+        .clone())
+}
+
+/// Check out `commit` and package it the way `cargo publish` would
+fn package_commit<'a>(
+    default_toolchain: &str,
+    git_repository: &'a GitRepository,
+    lock_info: &cargo_lock::Package,
+    package_dir: PackageDir<'_>,
+    commit: &str,
+) -> Result<(GitRepositoryCheckout<'a>, Package, PackageContents)> {
+    let git_repository_checkout = git_repository
+        .checkout(commit)
+        .map_err(|err| FindingKind::CheckoutFailed.or_context(err))?;
+    let package_dir = package_dir.resolve(git_repository_checkout.dir(), lock_info)?;
+
+    let repository_package = git_repository_checkout
+        .crate_package(
+            default_toolchain,
+            lock_info.name.as_str(),
+            &lock_info.version,
+            package_dir,
+        )
+        .context(FindingKind::PackageFailed)?;
+    let repository_package_contents = repository_package
+        .contents()
+        .context("calculate repository package contents")?;
+
+    Ok((
+        git_repository_checkout,
+        repository_package,
+        repository_package_contents,
+    ))
+}
+
+/// Ensure the manifest in `dir` declares the released package
+fn declares_package(
+    checkout_dir: &Path,
+    dir: &Path,
+    lock_info: &cargo_lock::Package,
+) -> Result<()> {
+    let manifest = Manifest::from_path(checkout_dir.join(dir).join("Cargo.toml"))
+        .with_context(|| format!("read manifest in `{}`", dir.display()))?;
+    let declared = manifest
+        .package
+        .as_ref()
+        .and_then(|package| Some((package.name.as_str(), package.version.get().ok()?.as_str())));
+    let expected_version = lock_info.version.to_string();
+    if declared != Some((lock_info.name.as_str(), expected_version.as_str())) {
+        let declared = match declared {
+            Some((name, version)) => format!("{name} v{version}"),
+            None => "no package".to_owned(),
+        };
+        bail!(
+            "`{}` declares {declared} instead of {} v{}",
+            dir.display(),
+            lock_info.name,
+            lock_info.version
+        );
+    }
+
+    Ok(())
+}
+
+/// Whether `comparison` doesn't make the packages differ
+fn is_equivalent(registry_crate: &RegistryCrate, comparison: &PackageComparison) -> bool {
+    match comparison {
+        PackageComparison::Equal(_) => true,
+        // `cargo vendor` drops these files
+        PackageComparison::OnlyLeft(path) => {
+            registry_crate.is_vendored()
+                && path
+                    .file_name()
+                    .is_some_and(|name| name == ".gitignore" || name == ".gitattributes")
+        }
+        PackageComparison::Different(_) | PackageComparison::OnlyRight(_) => false,
+    }
+}
+
+/// Read `.cargo_vcs_info.json` and `Cargo.toml` from the root of a package
+///
+/// A `.cargo_vcs_info.json` which can't be parsed is returned as an error
+/// alongside the manifest, so that it can be reported without failing.
+fn read_package_metadata(package: &Package) -> Result<(Option<Result<CargoVcsInfo>>, Manifest)> {
+    let mut cargo_vcs_info = None;
+    let mut cargo_toml = None;
+
+    let mut tar = package.archive_reader()?;
+    for entry in tar.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.into_owned();
+
+        // TODO: verify that the `.tar` doesn't contain multiple directories
+
+        let mut components = path.components().skip(1);
+        let (Some(file_name), None) = (components.next(), components.next()) else {
+            continue;
+        };
+
+        if file_name.as_os_str() == ".cargo_vcs_info.json" {
+            ensure!(
+                cargo_vcs_info.is_none(),
+                "`.cargo_vcs_info.json` encountered multiple times"
+            );
+
+            cargo_vcs_info = Some(
+                serde_json::from_reader::<_, CargoVcsInfo>(&mut entry)
+                    .map_err(anyhow::Error::from)
+                    .and_then(|cargo_vcs_info| {
+                        let sha1 = &cargo_vcs_info.git.sha1;
+                        ensure!(
+                            sha1.len() == 40 && sha1.bytes().all(|b| b.is_ascii_hexdigit()),
+                            "`{sha1}` isn't a commit id"
+                        );
+                        Ok(cargo_vcs_info)
+                    }),
+            );
+        } else if file_name.as_os_str() == "Cargo.toml" {
+            ensure!(
+                cargo_toml.is_none(),
+                "`Cargo.toml` encountered multiple times"
+            );
+
+            let mut manifest = String::new();
+            entry.read_to_string(&mut manifest)?;
+            cargo_toml = Some(Manifest::from_str(&manifest)?);
+        }
+    }
+
+    let manifest = cargo_toml.context("`Cargo.toml` not found")?;
+    Ok((cargo_vcs_info, manifest))
+}
+
 fn analyze_package(
     default_toolchain: &str,
+    config: &Config,
+    show_diff: bool,
     resolved_package: &ResolvedPackage,
-    git_repository: &mut GitRepository,
+    git_repository: &GitRepository,
+    report: &mut PackageReport,
 ) -> Result<()> {
     let ResolvedPackage {
         lock_info,
         registry_crate,
         repository_url: _,
+        subdirectory_hint,
         cargo_vcs_info,
+        manifest: registry_manifest,
     } = resolved_package;
 
     let registry_crate_package = registry_crate.package();
 
+    let path_in_vcs = cargo_vcs_info
+        .as_ref()
+        .and_then(|cargo_vcs_info| cargo_vcs_info.path_in_vcs.as_deref());
+    if let Some(path_in_vcs) = path_in_vcs {
+        if !path_in_vcs
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+        {
+            return Err(anyhow!(
+                "`path_in_vcs` points outside of the repository: `{}`",
+                path_in_vcs.display()
+            )
+            .context(FindingKind::InvalidCrate));
+        }
+    }
+    let package_dir = match (path_in_vcs, subdirectory_hint) {
+        (Some(path_in_vcs), _) => PackageDir::Recorded(path_in_vcs),
+        (None, Some(subdirectory_hint)) => PackageDir::Hinted(subdirectory_hint),
+        (None, None) => PackageDir::Unknown,
+    };
+
+    //
+    // Hash file contents
+    //
+
+    let registry_package_contents = registry_crate_package
+        .contents()
+        .context("calculate registry crate package contents")
+        .context(FindingKind::InvalidCrate)?;
+
     //
     // Get git tags
     //
 
     let tags = git_repository.tags().context("obtain git tags")?;
 
+    //
+    // Check that the commit in `.cargo_vcs_info.json` is part of the repository
+    //
+
+    if let Some(cargo_vcs_info) = &cargo_vcs_info {
+        let status = git_repository
+            .commit_status(&cargo_vcs_info.git.sha1)
+            .context("look up `.cargo_vcs_info.json` commit")?;
+        report.vcs_info_commit_status = Some(status);
+
+        match status {
+            CommitStatus::Reachable => {}
+            CommitStatus::Unreachable => report.finding(
+                FindingKind::VcsInfoCommitUnreachable,
+                format!(
+                    "Commit {} of {} v{} isn't reachable from any branch or tag of the repository",
+                    cargo_vcs_info.git.sha1, lock_info.name, lock_info.version
+                ),
+            ),
+            CommitStatus::Missing => report.finding(
+                FindingKind::VcsInfoCommitMissing,
+                format!(
+                    "Commit {} of {} v{} isn't in the repository, it was likely published from an unpushed commit or from a fork",
+                    cargo_vcs_info.git.sha1, lock_info.name, lock_info.version
+                ),
+            ),
+        }
+    }
+
     //
     // Find a matching tag
     //
 
-    let commit = match tags.find_tag_for_version(lock_info.name.as_str(), lock_info.version.clone())
-    {
-        Some(tag) => {
-            let commit = tag.commit()?;
+    let matching_tags = tags
+        .find_tags_for_version(
+            lock_info.name.as_str(),
+            lock_info.version.clone(),
+            config.tag_patterns(lock_info.name.as_str()),
+        )
+        .into_iter()
+        // Tags pointing at something other than a commit can't be a release
+        .filter_map(|(tag, pattern)| Some((tag, pattern, tag.commit().ok()?)))
+        .collect::<Vec<_>>();
+
+    let commit = match matching_tags.first() {
+        Some((tag, pattern, commit)) => {
+            report.tag = Some(tag.name().to_owned());
+            report.tag_pattern = Some((*pattern).to_owned());
+            report.tag_commit = Some(commit.clone());
 
             if let Some(cargo_vcs_info) = &cargo_vcs_info {
-                if cargo_vcs_info.git.sha1 != commit {
-                    println!(
-                        "Commit between crates.io tarball and git tag doesn't match for {} v{}",
-                        lock_info.name, lock_info.version
+                if cargo_vcs_info.git.sha1 != *commit {
+                    if report.vcs_info_commit_status != Some(CommitStatus::Missing) {
+                        report.vcs_info_relationship = Some(
+                            git_repository
+                                .relationship(commit, &cargo_vcs_info.git.sha1)
+                                .context("compare tag and `.cargo_vcs_info.json` commits")?,
+                        );
+                    }
+
+                    let relationship = match report.vcs_info_relationship {
+                        Some(CommitRelationship::Ancestor { distance }) => {
+                            format!("the tag is {distance} commit(s) behind the published commit")
+                        }
+                        Some(CommitRelationship::Descendant { distance }) => {
+                            format!("the tag is {distance} commit(s) ahead of the published commit")
+                        }
+                        Some(CommitRelationship::Diverged { ahead, behind }) => format!(
+                            "the tag and the published commit diverged ({ahead} commit(s) ahead, {behind} behind)"
+                        ),
+                        None => "the published commit isn't in the repository".to_owned(),
+                    };
+                    report.finding(
+                        FindingKind::VcsInfoCommitMismatch,
+                        format!(
+                            "Commit between crates.io tarball and git tag doesn't match for {} v{} (tag {} matched by `{pattern}`), {relationship}",
+                            lock_info.name, lock_info.version, tag.name()
+                        ),
                     );
                 }
             }
 
-            commit
+            commit.clone()
         }
         None => {
-            if tags.is_empty() {
-                println!("Package {} has no tags in git repository", lock_info.name);
-            } else {
-                println!("Found NO tag match with package {}", lock_info.name);
-            }
+            report.finding(
+                FindingKind::MissingTag,
+                if tags.is_empty() {
+                    format!("Package {} has no tags in git repository", lock_info.name)
+                } else {
+                    format!("Found NO tag match with package {}", lock_info.name)
+                },
+            );
+
+            match cargo_vcs_info {
+                Some(cargo_vcs_info) => {
+                    if report.vcs_info_commit_status == Some(CommitStatus::Missing) {
+                        return Err(anyhow!(
+                            "commit {} from `.cargo_vcs_info.json` isn't in the repository",
+                            cargo_vcs_info.git.sha1
+                        )
+                        .context(FindingKind::UnknownCommit));
+                    }
 
-            cargo_vcs_info
-                .as_ref()
-                .context("couldn't determine commit matching registry release")?
-                .git
-                .sha1
-                .clone()
+                    cargo_vcs_info.git.sha1.clone()
+                }
+                None => {
+                    let commit = locate_commit(
+                        default_toolchain,
+                        git_repository,
+                        registry_crate,
+                        &registry_package_contents,
+                        lock_info,
+                        package_dir,
+                    )
+                    .context(FindingKind::UnknownCommit)?;
+                    report.heuristic_commit = Some(commit.clone());
+                    report.finding(
+                        FindingKind::HeuristicCommit,
+                        format!(
+                            "Package {} v{} has neither a tag nor `.cargo_vcs_info.json`, commit {commit} was located by searching the history for its version",
+                            lock_info.name, lock_info.version
+                        ),
+                    );
+
+                    commit
+                }
+            }
         }
     };
 
     //
-    // Checkout the commit in the repo
+    // Checkout the commit in the repo and create local package
     //
 
-    let git_repository_checkout = git_repository
-        .checkout(&commit)
-        .context("couldn't checkout commit")?;
+    // Kept as a result, the other candidates are packaged even if this one fails
+    let released = package_commit(
+        default_toolchain,
+        git_repository,
+        lock_info,
+        package_dir,
+        &commit,
+    );
 
     //
-    // Create local package
+    // Package the commit in `.cargo_vcs_info.json` too when the tag points elsewhere
     //
 
-    let repository_package = git_repository_checkout
-        .crate_package(
+    let reproduces = |contents: &PackageContents| {
+        PackageContents::compare(contents, &registry_package_contents)
+            .all(|comparison| is_equivalent(registry_crate, &comparison))
+    };
+    report.reproducing_commit = released
+        .as_ref()
+        .is_ok_and(|(_, _, contents)| reproduces(contents))
+        .then(|| commit.clone());
+    // Another candidate which reproduces the package, when the released commit doesn't
+    let mut reproducing = None;
+
+    let vcs_info_commit = report
+        .vcs_info_relationship
+        .and(cargo_vcs_info.as_ref())
+        .map(|cargo_vcs_info| &cargo_vcs_info.git.sha1);
+    if let Some(vcs_info_commit) = vcs_info_commit {
+        if let Ok(vcs_info_packaged) = package_commit(
             default_toolchain,
-            lock_info.name.as_str(),
-            &lock_info.version,
-        )
-        .context("couldn't package")?;
+            git_repository,
+            lock_info,
+            package_dir,
+            vcs_info_commit,
+        ) {
+            let (_, _, vcs_info_contents) = &vcs_info_packaged;
+            if let Ok((_, _, released_contents)) = &released {
+                report.vcs_info_changed_files = Some(
+                    PackageContents::compare(released_contents, vcs_info_contents)
+                        .filter(|comparison| !matches!(comparison, PackageComparison::Equal(_)))
+                        .map(|comparison| comparison.path().to_owned())
+                        .collect(),
+                );
+            }
+            if report.reproducing_commit.is_none() && reproduces(vcs_info_contents) {
+                report.reproducing_commit = Some(vcs_info_commit.clone());
+                reproducing = Some(vcs_info_packaged);
+            }
+        }
+    }
 
     //
-    // Hash file contents
+    // Find out which of the tagged commits reproduces the package
     //
 
-    let repository_package_contents = repository_package
-        .contents()
-        .context("calculate repository package contents")?;
-    let registry_package_contents = registry_crate_package
-        .contents()
-        .context("calculate registry crate package contents")?;
+    let other_commits = matching_tags
+        .iter()
+        .map(|(_, _, tag_commit)| tag_commit)
+        .filter(|&tag_commit| *tag_commit != commit)
+        .collect::<BTreeSet<_>>();
+    if !other_commits.is_empty() {
+        if report.reproducing_commit.is_none() {
+            (report.reproducing_commit, reproducing) = other_commits
+                .iter()
+                .filter(|&&other_commit| Some(other_commit) != vcs_info_commit)
+                .find_map(|&other_commit| {
+                    let other_packaged = package_commit(
+                        default_toolchain,
+                        git_repository,
+                        lock_info,
+                        package_dir,
+                        other_commit,
+                    )
+                    .ok()?;
+                    reproduces(&other_packaged.2)
+                        .then(|| (Some(other_commit.clone()), Some(other_packaged)))
+                })
+                .unwrap_or_default();
+        }
+
+        let tags = matching_tags
+            .iter()
+            .map(|(tag, _, tag_commit)| format!("{}={tag_commit}", tag.name()))
+            .collect::<Vec<_>>()
+            .join(", ");
+        report.finding(
+            FindingKind::AmbiguousTag,
+            match &report.reproducing_commit {
+                Some(reproducing_commit) => format!(
+                    "Package {} v{} has tags pointing at different commits ({tags}), {reproducing_commit} reproduces the registry package",
+                    lock_info.name, lock_info.version
+                ),
+                None => format!(
+                    "Package {} v{} has tags pointing at different commits ({tags}), none of them reproduces the registry package",
+                    lock_info.name, lock_info.version
+                ),
+            },
+        );
+    }
+
+    // Files are compared with the commit which reproduces the package, if any,
+    // the tags pointing elsewhere are only warned about
+    let (git_repository_checkout, repository_package, repository_package_contents) =
+        match reproducing {
+            Some(reproducing) => reproducing,
+            None => released?,
+        };
 
     //
     // Compare hashes
     //
 
-    let comparison =
-        PackageContents::compare(&repository_package_contents, &registry_package_contents);
-    for outcome in comparison {
-        match outcome {
-            PackageComparison::Equal(_) => continue,
-            PackageComparison::Different(path) => {
-                println!(
-                    "Package {} has mismatching file hashes for {}",
-                    lock_info.name,
-                    path.display()
-                );
-            }
-            PackageComparison::OnlyLeft(path) => {
-                println!(
-                    "Package {} has file {} in our release but not in crates.io tarball",
-                    lock_info.name,
-                    path.display()
+    // A commit located by searching the history may not be the released one,
+    // so differences from it don't prove the package mismatches
+    let mismatch = |kind| {
+        if report.heuristic_commit.is_some() && report.reproducing_commit.is_none() {
+            FindingKind::UnknownCommit
+        } else {
+            kind
+        }
+    };
+    let file_mismatch_kind = mismatch(FindingKind::FileMismatch);
+    let manifest_mismatch_kind = mismatch(FindingKind::ManifestMismatch);
+    let manifest_orig_mismatch_kind = mismatch(FindingKind::ManifestOrigMismatch);
+
+    // Kept on the report right away, so that it's complete even if a later step fails
+    report.comparisons =
+        PackageContents::compare(&repository_package_contents, &registry_package_contents)
+            .collect();
+    let file_mismatches = report
+        .comparisons
+        .iter()
+        .filter(|comparison| !is_equivalent(registry_crate, comparison))
+        .filter_map(|comparison| match comparison {
+            PackageComparison::Equal(_) => None,
+            PackageComparison::Different(path) => Some(format!(
+                "Package {} has mismatching file hashes for {}",
+                lock_info.name,
+                path.display()
+            )),
+            PackageComparison::OnlyLeft(path) => Some(format!(
+                "Package {} has file {} in our release but not in crates.io tarball",
+                lock_info.name,
+                path.display()
+            )),
+            PackageComparison::OnlyRight(path) => Some(format!(
+                "Package {} has file {} in crates.io release but not ours",
+                lock_info.name,
+                path.display()
+            )),
+        })
+        .collect::<Vec<_>>();
+    for message in file_mismatches {
+        report.finding(file_mismatch_kind, message);
+    }
+
+    //
+    // Compare the normalized manifests
+    //
+
+    let (_, repository_manifest) =
+        read_package_metadata(&repository_package).context("read repository package manifest")?;
+    let manifest_differences = manifest::compare(
+        &repository_manifest,
+        &repository_package_contents,
+        registry_manifest,
+        &registry_package_contents,
+    )
+    .context("compare manifests")?;
+    let manifest_mismatch = !manifest_differences.is_empty();
+    for ManifestDifference { key, left, right } in manifest_differences {
+        report.finding(
+            manifest_mismatch_kind,
+            format!(
+                "Package {} has a different `{key}` in its Cargo.toml: ours={left} crates.io={right}",
+                lock_info.name
+            ),
+        );
+    }
+
+    //
+    // Compare `Cargo.toml.orig` with the manifest in the repository
+    //
+
+    let mut manifest_orig_mismatch = false;
+    if let Some(registry_manifest_orig) = registry_crate_package
+        .read_root_file("Cargo.toml.orig")
+        .context("read registry crate `Cargo.toml.orig`")
+        .context(FindingKind::InvalidCrate)?
+    {
+        let package_dir = package_dir.resolve(git_repository_checkout.dir(), lock_info)?;
+        // Only ask cargo when the package wasn't found in a known directory
+        let manifest_path =
+            if declares_package(git_repository_checkout.dir(), package_dir, lock_info).is_ok() {
+                package_dir.join("Cargo.toml")
+            } else {
+                git_repository_checkout
+                    .manifest_path(
+                        default_toolchain,
+                        lock_info.name.as_str(),
+                        &lock_info.version,
+                        package_dir,
+                    )
+                    .context("locate manifest in repository")?
+            };
+        let repository_manifest_orig = fs::read(git_repository_checkout.dir().join(&manifest_path))
+            .context("read manifest in repository")?;
+
+        if repository_manifest_orig != registry_manifest_orig {
+            manifest_orig_mismatch = true;
+            let repository_manifest_orig = Manifest::from_slice(&repository_manifest_orig)
+                .context("parse manifest in repository")?;
+            let registry_manifest_orig = Manifest::from_slice(&registry_manifest_orig)
+                .context("parse registry crate `Cargo.toml.orig`")
+                .context(FindingKind::InvalidCrate)?;
+
+            if repository_manifest_orig == registry_manifest_orig {
+                report.finding(
+                    FindingKind::ManifestOrigFormatting,
+                    format!(
+                        "Package {} has a `Cargo.toml.orig` formatted differently from {}",
+                        lock_info.name,
+                        manifest_path.display()
+                    ),
                 );
-            }
-            PackageComparison::OnlyRight(path) => {
-                println!(
-                    "Package {} has file {} in crates.io release but not ours",
-                    lock_info.name,
-                    path.display()
+            } else {
+                report.finding(
+                    manifest_orig_mismatch_kind,
+                    format!(
+                        "Package {} has a `Cargo.toml.orig` which doesn't match {}",
+                        lock_info.name,
+                        manifest_path.display()
+                    ),
                 );
             }
         }
     }
 
+    //
+    // Diff mismatching files
+    //
+
+    if show_diff {
+        let package_root = PathBuf::from(format!("{}-{}", lock_info.name, lock_info.version));
+        let manifest = package_root.join("Cargo.toml");
+        let manifest_orig = package_root.join("Cargo.toml.orig");
+        let mut paths = report
+            .comparisons
+            .iter()
+            .filter(|comparison| !matches!(comparison, PackageComparison::Equal(_)))
+            .map(PackageComparison::path)
+            .collect::<BTreeSet<_>>();
+        // The manifests aren't compared file by file, only through their findings.
+        // `cargo package` copies the manifest in the repository to `Cargo.toml.orig`.
+        if manifest_mismatch {
+            paths.insert(&manifest);
+        }
+        if manifest_orig_mismatch {
+            paths.insert(&manifest_orig);
+        }
+        let repository_files = repository_package
+            .read_files(&paths)
+            .context("read repository package files")?;
+        let registry_files = registry_crate_package
+            .read_files(&paths)
+            .context("read registry crate package files")?;
+
+        report.diffs = paths
+            .into_iter()
+            .map(|path| {
+                FileDiff::new(
+                    path,
+                    repository_files.get(path).map(Vec::as_slice),
+                    registry_files.get(path).map(Vec::as_slice),
+                )
+            })
+            .collect();
+    }
+
     Ok(())
 }
@@ -0,0 +1,207 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashSet},
+    io,
+    path::Path,
+};
+
+use anyhow::{Context as _, Result};
+use cargo_toml::{
+    AbstractFilesystem, Dependency, DepsSet, Edition, Manifest, OptionalFile, Product,
+};
+use serde_json::Value;
+
+use crate::package::PackageContents;
+
+/// A key whose value differs between two normalized manifests
+#[derive(Debug)]
+pub struct ManifestDifference {
+    /// Dotted path of the key, e.g. `dependencies.serde` or `package.links`
+    pub key: String,
+    pub left: Value,
+    pub right: Value,
+}
+
+/// Semantically compare the normalized `Cargo.toml` of two packages
+///
+/// Both manifests are completed with the targets and build script cargo
+/// would auto-discover from the files of their package, so that manifests
+/// normalized by different cargo versions compare equal as long as they
+/// build the same thing. Only dependencies, features, targets, the build
+/// script, `links` and the `[package]` metadata are compared.
+pub fn compare(
+    left: &Manifest,
+    left_contents: &PackageContents,
+    right: &Manifest,
+    right_contents: &PackageContents,
+) -> Result<Vec<ManifestDifference>> {
+    let left = normalize(left, left_contents).context("normalize left manifest")?;
+    let right = normalize(right, right_contents).context("normalize right manifest")?;
+
+    let keys = left.keys().chain(right.keys()).collect::<BTreeSet<_>>();
+    Ok(keys
+        .into_iter()
+        .filter_map(|key| {
+            let left = left.get(key).cloned().unwrap_or_default();
+            let right = right.get(key).cloned().unwrap_or_default();
+            (left != right).then(|| ManifestDifference {
+                key: key.clone(),
+                left,
+                right,
+            })
+        })
+        .collect())
+}
+
+/// Flatten a manifest into the keys we compare
+fn normalize(manifest: &Manifest, contents: &PackageContents) -> Result<BTreeMap<String, Value>> {
+    let mut manifest = manifest.clone();
+    manifest.complete_from_abstract_filesystem::<Value, _>(PackageFilesystem(contents), None)?;
+
+    let mut entries = BTreeMap::new();
+
+    let package = manifest
+        .package
+        .as_ref()
+        .context("package metadata missing")?;
+    let build = match &package.build {
+        Some(OptionalFile::Path(path)) => Some(path.clone()),
+        Some(OptionalFile::Flag(_)) | None => None,
+    };
+    for (key, value) in [
+        ("name", serde_json::to_value(&package.name)?),
+        ("version", serde_json::to_value(&package.version)?),
+        ("edition", serde_json::to_value(package.edition)?),
+        ("rust-version", serde_json::to_value(&package.rust_version)?),
+        ("build", serde_json::to_value(build)?),
+        ("links", serde_json::to_value(&package.links)?),
+        ("authors", serde_json::to_value(&package.authors)?),
+        ("description", serde_json::to_value(&package.description)?),
+        ("homepage", serde_json::to_value(&package.homepage)?),
+        (
+            "documentation",
+            serde_json::to_value(&package.documentation)?,
+        ),
+        ("keywords", serde_json::to_value(&package.keywords)?),
+        ("categories", serde_json::to_value(&package.categories)?),
+        ("license", serde_json::to_value(&package.license)?),
+        ("license-file", serde_json::to_value(&package.license_file)?),
+        ("repository", serde_json::to_value(&package.repository)?),
+        ("default-run", serde_json::to_value(&package.default_run)?),
+        ("metadata", serde_json::to_value(&package.metadata)?),
+    ] {
+        entries.insert(format!("package.{key}"), value);
+    }
+
+    normalize_dependencies(&mut entries, "dependencies", &manifest.dependencies)?;
+    normalize_dependencies(&mut entries, "dev-dependencies", &manifest.dev_dependencies)?;
+    normalize_dependencies(
+        &mut entries,
+        "build-dependencies",
+        &manifest.build_dependencies,
+    )?;
+    for (cfg, target) in &manifest.target {
+        let prefix = format!("target.{cfg}");
+        normalize_dependencies(
+            &mut entries,
+            &format!("{prefix}.dependencies"),
+            &target.dependencies,
+        )?;
+        normalize_dependencies(
+            &mut entries,
+            &format!("{prefix}.dev-dependencies"),
+            &target.dev_dependencies,
+        )?;
+        normalize_dependencies(
+            &mut entries,
+            &format!("{prefix}.build-dependencies"),
+            &target.build_dependencies,
+        )?;
+    }
+
+    for (name, enables) in &manifest.features {
+        let mut enables = enables.clone();
+        enables.sort_unstable();
+        enables.dedup();
+        entries.insert(format!("features.{name}"), serde_json::to_value(enables)?);
+    }
+
+    let edition = *package.edition.get()?;
+    if let Some(lib) = &manifest.lib {
+        entries.insert("lib".to_owned(), normalize_product(lib, edition)?);
+    }
+    for (kind, products) in [
+        ("bin", &manifest.bin),
+        ("example", &manifest.example),
+        ("test", &manifest.test),
+        ("bench", &manifest.bench),
+    ] {
+        for product in products {
+            let name = product.name.as_deref().unwrap_or_default();
+            entries.insert(
+                format!("{kind}.{name}"),
+                normalize_product(product, edition)?,
+            );
+        }
+    }
+
+    Ok(entries)
+}
+
+fn normalize_dependencies(
+    entries: &mut BTreeMap<String, Value>,
+    prefix: &str,
+    dependencies: &DepsSet,
+) -> Result<()> {
+    for (name, dependency) in dependencies {
+        let mut detail = match dependency {
+            Dependency::Simple(version) => cargo_toml::DependencyDetail {
+                version: Some(version.clone()),
+                ..Default::default()
+            },
+            Dependency::Detailed(detail) => (**detail).clone(),
+            Dependency::Inherited(_) => {
+                anyhow::bail!("dependency `{name}` is inherited from the workspace")
+            }
+        };
+        // `cargo package` strips or rewrites `path`, and it is meaningless
+        // to anyone depending on the package from the registry
+        detail.path = None;
+        detail.features.sort_unstable();
+        detail.features.dedup();
+
+        entries.insert(format!("{prefix}.{name}"), serde_json::to_value(detail)?);
+    }
+
+    Ok(())
+}
+
+fn normalize_product(product: &Product, edition: Edition) -> Result<Value> {
+    let mut product = product.clone();
+    product.edition.get_or_insert(edition);
+    product.crate_type.sort_unstable();
+    product.required_features.sort_unstable();
+    Ok(serde_json::to_value(product)?)
+}
+
+/// Lets `cargo_toml` auto-discover targets from the files of a package
+struct PackageFilesystem<'a>(&'a PackageContents);
+
+impl AbstractFilesystem for PackageFilesystem<'_> {
+    fn file_names_in(&self, rel_path: &str) -> io::Result<HashSet<Box<str>>> {
+        let dir = Path::new(rel_path);
+        let names = self
+            .0
+            .relative_paths()
+            .filter_map(|path| path.strip_prefix(dir).ok())
+            .filter_map(|path| path.components().next())
+            .filter_map(|name| name.as_os_str().to_str())
+            .map(Box::from)
+            .collect::<HashSet<_>>();
+
+        if names.is_empty() {
+            Err(io::ErrorKind::NotFound.into())
+        } else {
+            Ok(names)
+        }
+    }
+}
@@ -1,11 +1,12 @@
 use std::{
-    collections::BTreeMap,
+    collections::{BTreeMap, BTreeSet},
     fs::File,
     io::{self, BufReader, Read, Seek},
     path::{Path, PathBuf},
 };
 
 use flate2::read::GzDecoder;
+use serde::Serialize;
 use sha2::{Digest as _, Sha512};
 use tar::Archive;
 
@@ -17,9 +18,10 @@ pub struct Package(PathBuf);
 #[derive(Debug)]
 pub struct PackageContents(BTreeMap<PathBuf, [u8; 64]>);
 
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
+#[serde(tag = "outcome", content = "path", rename_all = "snake_case")]
 pub enum PackageComparison {
-    Equal(#[allow(dead_code)] PathBuf),
+    Equal(PathBuf),
     Different(PathBuf),
     OnlyLeft(PathBuf),
     OnlyRight(PathBuf),
@@ -59,9 +61,71 @@ impl Package {
 
         Ok(PackageContents(hashes))
     }
+
+    /// Read the raw contents of a file at the root of the package
+    pub fn read_root_file(&self, name: &str) -> io::Result<Option<Vec<u8>>> {
+        let mut archive = self.archive_reader()?;
+        for file in archive.entries()? {
+            let mut file = file?;
+            let path = file.path()?.into_owned();
+
+            let mut components = path.components().skip(1);
+            if let (Some(file_name), None) = (components.next(), components.next()) {
+                if file_name.as_os_str() == name {
+                    let mut contents = Vec::new();
+                    file.read_to_end(&mut contents)?;
+                    return Ok(Some(contents));
+                }
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Read the raw contents of the requested files
+    ///
+    /// Files which aren't part of the package are left out of the result.
+    pub fn read_files(&self, paths: &BTreeSet<&Path>) -> io::Result<BTreeMap<PathBuf, Vec<u8>>> {
+        let mut files = BTreeMap::new();
+
+        let mut archive = self.archive_reader()?;
+        for file in archive.entries()? {
+            let mut file = file?;
+            let path = file.path()?.into_owned();
+            if !paths.contains(path.as_path()) {
+                continue;
+            }
+
+            let mut contents = Vec::new();
+            file.read_to_end(&mut contents)?;
+            files.insert(path, contents);
+        }
+
+        Ok(files)
+    }
+}
+
+impl PackageComparison {
+    pub fn path(&self) -> &Path {
+        match self {
+            Self::Equal(path)
+            | Self::Different(path)
+            | Self::OnlyLeft(path)
+            | Self::OnlyRight(path) => path,
+        }
+    }
 }
 
 impl PackageContents {
+    /// Paths of the files in the package, relative to the package root
+    pub fn relative_paths(&self) -> impl Iterator<Item = &Path> {
+        self.0.keys().map(|path| {
+            let mut components = path.components();
+            components.next();
+            components.as_path()
+        })
+    }
+
     pub fn compare<'a>(
         left: &'a PackageContents,
         right: &'a PackageContents,
@@ -93,10 +157,16 @@ impl PackageContents {
     }
 }
 
+/// Whether `path` is one of the files at the root of the package which cargo
+/// generates, and which are checked separately
+///
+/// Files with the same names in subdirectories are compared like any other.
 fn is_path_ignored(path: &Path) -> bool {
-    path.file_name().is_some_and(|name| {
-        [".cargo_vcs_info.json", "Cargo.toml"]
-            .into_iter()
-            .any(|n| n == name)
-    })
+    let mut components = path.components().skip(1);
+    let (Some(file_name), None) = (components.next(), components.next()) else {
+        return false;
+    };
+    [".cargo_vcs_info.json", "Cargo.toml", "Cargo.toml.orig"]
+        .into_iter()
+        .any(|name| file_name.as_os_str() == name)
 }
@@ -0,0 +1,83 @@
+use std::str::FromStr;
+
+use anyhow::{bail, Error};
+use serde::Serialize;
+
+use crate::verdict::{FindingKind, Verdict};
+
+/// Whether a finding only gets reported or also fails the run
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Level {
+    #[default]
+    Warn,
+    Deny,
+}
+
+/// Selects findings either by their kind or by the verdict they imply
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Selector {
+    Kind(FindingKind),
+    Verdict(Verdict),
+}
+
+/// Maps each finding kind to a [`Level`]
+///
+/// Selecting a kind takes precedence over selecting a verdict, so that
+/// `--deny unverifiable --warn unsupported-source` denies every unverifiable
+/// package except the ones coming from git or other unsupported sources.
+/// Within the same precedence `warn` wins over `deny`. Anything not selected
+/// is a warning.
+#[derive(Debug, Default)]
+pub struct Policy {
+    deny: Vec<Selector>,
+    warn: Vec<Selector>,
+}
+
+impl Policy {
+    pub fn new(deny: Vec<Selector>, warn: Vec<Selector>) -> Self {
+        Self { deny, warn }
+    }
+
+    pub fn level(&self, kind: FindingKind) -> Level {
+        let by_kind = Selector::Kind(kind);
+        let by_verdict = Selector::Verdict(kind.verdict());
+
+        [by_kind, by_verdict]
+            .into_iter()
+            .find_map(|selector| {
+                if self.warn.contains(&selector) {
+                    Some(Level::Warn)
+                } else if self.deny.contains(&selector) {
+                    Some(Level::Deny)
+                } else {
+                    None
+                }
+            })
+            .unwrap_or_default()
+    }
+}
+
+impl FromStr for Selector {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(kind) = FindingKind::ALL.into_iter().find(|kind| kind.as_str() == s) {
+            return Ok(Self::Kind(kind));
+        }
+
+        if let Some(verdict) = [
+            Verdict::Skipped,
+            Verdict::ReproducibleWithWarnings,
+            Verdict::Unverifiable,
+            Verdict::Mismatch,
+        ]
+        .into_iter()
+        .find(|verdict| verdict.as_str() == s)
+        {
+            return Ok(Self::Verdict(verdict));
+        }
+
+        bail!("unknown finding kind or verdict `{s}`")
+    }
+}
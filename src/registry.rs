@@ -1,39 +1,334 @@
 use std::{
+    collections::BTreeMap,
     fs::{self, File},
     io,
     path::{Path, PathBuf},
+    time::SystemTime,
 };
 
-use anyhow::Result;
+use anyhow::{anyhow, bail, Context as _, Result};
+use cargo_lock::{package::SourceKind, Checksum, SourceId};
+use flate2::{write::GzEncoder, Compression};
+use reqwest::header::AUTHORIZATION;
 use semver::Version;
+use serde::Deserialize;
+use sha2::{Digest as _, Sha256};
 
+use crate::cargo_config::CargoConfig;
+use crate::git;
 use crate::package::Package;
+use crate::verdict::FindingKind;
+
+const CRATES_IO_DL: &str = "https://static.crates.io/crates";
+/// Where the index of a git registry is cloned, next to its crates, under a
+/// name no crate can have
+pub const INDEX_DIR: &str = "index.git";
+
+/// Where `.crate` files may be found without downloading them
+#[derive(Debug, Default)]
+pub struct CrateSources {
+    /// Directories of `<name>-<version>.crate` files, looked up before cargo's own cache
+    pub crates_dirs: Vec<PathBuf>,
+    /// A directory populated by `cargo vendor`
+    pub vendor_dir: Option<PathBuf>,
+    /// Never download anything, only use local files
+    pub offline: bool,
+}
+
+/// A registry packages in Cargo.lock can be downloaded from
+#[derive(Debug)]
+pub struct Registry {
+    /// Where `.crate` files from this registry are cached
+    cache_dir: PathBuf,
+    /// Directories of `.crate` files which may come from this registry, including cargo's cache
+    crates_dirs: Vec<PathBuf>,
+    vendor_dir: Option<PathBuf>,
+    /// `None` in offline mode
+    config: Option<RegistryConfig>,
+    token: Option<String>,
+}
+
+/// The `config.json` at the root of a registry index
+#[derive(Debug, Deserialize)]
+struct RegistryConfig {
+    dl: String,
+    #[serde(default, rename = "auth-required")]
+    auth_required: bool,
+}
 
 #[derive(Debug)]
 pub struct RegistryCrate {
     crate_file: PathBuf,
+    /// Whether `crate_file` was repacked from a vendor directory
+    vendored: bool,
+}
+
+/// The `.cargo-checksum.json` written by `cargo vendor` in every package
+#[derive(Debug, Deserialize)]
+struct VendorChecksums {
+    files: BTreeMap<String, String>,
+    package: Option<String>,
+}
+
+impl Registry {
+    /// Read the configuration of the registry behind `source`
+    ///
+    /// `cache_dir` is used for downloaded `.crate` files and, for git
+    /// indexes, for a shallow clone of the index. The git and sparse indexes
+    /// of crates.io are the same registry and share their cache. In offline
+    /// mode nothing is fetched, so only local `.crate` files can be used.
+    pub fn load(
+        http_client: &reqwest::blocking::Client,
+        cache_dir: &Path,
+        cargo_config: &CargoConfig,
+        crate_sources: &CrateSources,
+        source: &SourceId,
+    ) -> Result<Self> {
+        let index = match source.kind() {
+            SourceKind::Registry => source.url().to_string(),
+            SourceKind::SparseRegistry => format!("sparse+{}", source.url()),
+            _ => bail!("`{source}` isn't a remote registry"),
+        };
+        let token = cargo_config.token(&index);
+
+        let url = source.url();
+        let key = if source.is_default_registry() {
+            "crates.io".to_owned()
+        } else {
+            format!(
+                "{}-{:.16x}",
+                url.host_str().unwrap_or("local"),
+                Sha256::digest(index.as_bytes())
+            )
+        };
+        let cache_dir = cache_dir.join(key);
+        fs::create_dir_all(&cache_dir)?;
+
+        let config = if crate_sources.offline {
+            None
+        } else if source.is_default_registry() {
+            // Avoid fetching the index just to read `config.json`
+            Some(RegistryConfig {
+                dl: CRATES_IO_DL.to_owned(),
+                auth_required: false,
+            })
+        } else if source.kind() == &SourceKind::SparseRegistry {
+            let mut req = http_client.get(url.join("config.json")?);
+            if let Some(token) = &token {
+                req = req.header(AUTHORIZATION, token);
+            }
+            let config = req.send()?.error_for_status()?.bytes()?;
+            Some(serde_json::from_slice(&config).context("couldn't parse registry `config.json`")?)
+        } else {
+            let index_dir = cache_dir.join(INDEX_DIR);
+            git::clone_registry_index(url.as_str(), &index_dir)?;
+
+            let config = git::read_head_file(&index_dir, "config.json")
+                .context("couldn't read registry `config.json`")?;
+            Some(serde_json::from_slice(&config).context("couldn't parse registry `config.json`")?)
+        };
+
+        let mut crates_dirs = crate_sources.crates_dirs.clone();
+        crates_dirs.extend(
+            cargo_cache_dirs(cargo_config.cargo_home(), source)
+                .context("couldn't list cargo's registry cache")?,
+        );
+
+        Ok(Self {
+            cache_dir,
+            crates_dirs,
+            vendor_dir: crate_sources.vendor_dir.clone(),
+            config,
+            token,
+        })
+    }
+
+    /// Find a `.crate` already downloaded by cargo or provided by the user
+    ///
+    /// Without a checksum to verify it against, a local file could come from
+    /// another registry, so it isn't used.
+    fn find_local(
+        &self,
+        name: &str,
+        version: &Version,
+        checksum: Option<&Checksum>,
+    ) -> Result<Option<PathBuf>> {
+        let Some(Checksum::Sha256(checksum)) = checksum else {
+            return Ok(None);
+        };
+
+        for dir in &self.crates_dirs {
+            let crate_path = dir.join(format!("{name}-{version}.crate"));
+            let mut crate_file = match File::open(&crate_path) {
+                Ok(crate_file) => crate_file,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+                Err(err) => return Err(err.into()),
+            };
+
+            let mut sha256 = Sha256::new();
+            io::copy(&mut crate_file, &mut sha256)?;
+            if <[u8; 32]>::from(sha256.finalize()) == *checksum {
+                return Ok(Some(crate_path));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Find a package in the vendor directory and repack it into a `.crate`
+    ///
+    /// `cargo vendor` records the checksum of the original `.crate` along
+    /// with the checksum of every file it extracted, so the vendored files are
+    /// verified against both before being repacked.
+    fn find_vendored(
+        &self,
+        name: &str,
+        version: &Version,
+        checksum: Option<&Checksum>,
+    ) -> Result<Option<PathBuf>> {
+        let (Some(vendor_dir), Some(Checksum::Sha256(checksum))) = (&self.vendor_dir, checksum)
+        else {
+            return Ok(None);
+        };
+
+        for package_dir in [
+            vendor_dir.join(format!("{name}-{version}")),
+            vendor_dir.join(name),
+        ] {
+            let checksums = match fs::read(package_dir.join(".cargo-checksum.json")) {
+                Ok(checksums) => checksums,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+                Err(err) => return Err(err.into()),
+            };
+            let checksums = serde_json::from_slice::<VendorChecksums>(&checksums)
+                .context("couldn't parse `.cargo-checksum.json`")?;
+            if checksums.package.as_deref() != Some(hex(checksum).as_str()) {
+                continue;
+            }
+
+            let prefix = format!("{name}-{version}");
+            let mut archive = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::fast()));
+            for (path, file_checksum) in &checksums.files {
+                let contents = fs::read(package_dir.join(path))
+                    .with_context(|| format!("couldn't read vendored file {path}"))?;
+                if hex(&Sha256::digest(&contents)) != *file_checksum {
+                    return Err(anyhow!("vendored file {path} was modified")
+                        .context(FindingKind::ChecksumMismatch));
+                }
+
+                let mut header = tar::Header::new_gnu();
+                header.set_size(contents.len() as u64);
+                header.set_mode(0o644);
+                archive.append_data(
+                    &mut header,
+                    format!("{prefix}/{path}"),
+                    contents.as_slice(),
+                )?;
+            }
+            let archive = archive.into_inner()?.finish()?;
+
+            let crate_path = self
+                .cache_dir
+                .join(name)
+                .join(format!("{version}.vendored.tar.gz"));
+            write_atomically(&crate_path, &archive)?;
+            return Ok(Some(crate_path));
+        }
+
+        Ok(None)
+    }
+
+    /// Build the download url of a `.crate` from the `dl` template
+    fn download_url(
+        &self,
+        config: &RegistryConfig,
+        name: &str,
+        version: &Version,
+        checksum: Option<&Checksum>,
+    ) -> String {
+        const MARKERS: [&str; 5] = [
+            "{crate}",
+            "{version}",
+            "{prefix}",
+            "{lowerprefix}",
+            "{sha256-checksum}",
+        ];
+
+        let dl = &config.dl;
+        if !MARKERS.iter().any(|marker| dl.contains(marker)) {
+            return format!("{}/{name}/{version}/download", dl.trim_end_matches('/'));
+        }
+
+        let prefix = match name.len() {
+            1 => "1".to_owned(),
+            2 => "2".to_owned(),
+            3 => format!("3/{}", &name[..1]),
+            _ => format!("{}/{}", &name[..2], &name[2..4]),
+        };
+        let checksum = match checksum {
+            Some(Checksum::Sha256(checksum)) => hex(checksum),
+            None => String::new(),
+        };
+
+        dl.replace("{crate}", name)
+            .replace("{version}", &version.to_string())
+            .replace("{lowerprefix}", &prefix.to_lowercase())
+            .replace("{prefix}", &prefix)
+            .replace("{sha256-checksum}", &checksum)
+    }
 }
 
 impl RegistryCrate {
+    /// Look for the `.crate` locally, then download it if allowed to
+    ///
+    /// Local `.crate` files are looked up in the crates directories, then in
+    /// the vendor directory, then in the download cache.
     pub fn obtain(
         http_client: &reqwest::blocking::Client,
-        cache_dir: &Path,
+        registry: &Registry,
         name: &str,
         version: &Version,
+        checksum: Option<&Checksum>,
     ) -> Result<Self> {
-        let crate_dir = cache_dir.join(name);
+        if let Some(crate_path) = registry.find_local(name, version, checksum)? {
+            return Ok(Self {
+                crate_file: crate_path,
+                vendored: false,
+            });
+        }
+
+        let crate_dir = registry.cache_dir.join(name);
         if !crate_dir.try_exists()? {
             fs::create_dir(&crate_dir)?;
         }
 
+        if let Some(crate_path) = registry.find_vendored(name, version, checksum)? {
+            return Ok(Self {
+                crate_file: crate_path,
+                vendored: true,
+            });
+        }
+
         let crate_path = crate_dir.join(format!("{version}.tar.gz"));
-        if !crate_path.try_exists()? {
-            let mut resp = http_client
-                .get(format!(
-                    "https://static.crates.io/crates/{name}/{name}-{version}.crate",
-                ))
-                .send()?
-                .error_for_status()?;
+        if crate_path.try_exists()? {
+            // Tell `cargo goggles cache prune` it's still in use
+            File::options()
+                .write(true)
+                .open(&crate_path)?
+                .set_modified(SystemTime::now())?;
+        } else {
+            let config = registry.config.as_ref().ok_or_else(|| {
+                anyhow!("{name} v{version} isn't available locally")
+                    .context(FindingKind::UnavailableOffline)
+            })?;
+
+            let mut req = http_client.get(registry.download_url(config, name, version, checksum));
+            if config.auth_required {
+                if let Some(token) = &registry.token {
+                    req = req.header(AUTHORIZATION, token);
+                }
+            }
+            let mut resp = req.send()?.error_for_status()?;
 
             let mut tmp_crate_path = crate_path.clone();
             tmp_crate_path.as_mut_os_string().push(".tmp");
@@ -48,10 +343,64 @@ impl RegistryCrate {
 
         Ok(Self {
             crate_file: crate_path,
+            vendored: false,
         })
     }
 
     pub fn package(&self) -> Package {
         Package::new(self.crate_file.clone())
     }
+
+    /// Whether the package was repacked from a vendor directory
+    ///
+    /// Its checksum was then verified against `.cargo-checksum.json`, and the
+    /// archive itself won't match the checksum in Cargo.lock.
+    pub fn is_vendored(&self) -> bool {
+        self.vendored
+    }
+}
+
+fn write_atomically(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut tmp_path = path.to_owned();
+    tmp_path.as_mut_os_string().push(".tmp");
+
+    fs::write(&tmp_path, contents)?;
+    fs::rename(tmp_path, path)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// List the directories of `$CARGO_HOME/registry/cache` which may belong to `source`
+///
+/// Cargo names them `<host>-<hash>`, where the hash depends on the cargo
+/// version, so they are matched on the host only.
+pub fn cargo_cache_dirs(cargo_home: &Path, source: &SourceId) -> Result<Vec<PathBuf>> {
+    let hosts = if source.is_default_registry() {
+        vec!["index.crates.io", "github.com"]
+    } else {
+        vec![source.url().host_str().unwrap_or("local")]
+    };
+
+    let entries = match fs::read_dir(cargo_home.join("registry").join("cache")) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err.into()),
+    };
+
+    let mut dirs = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        let file_name = entry.file_name();
+        let Some((host, _hash)) = file_name.to_str().and_then(|name| name.rsplit_once('-')) else {
+            continue;
+        };
+        if hosts.contains(&host) && entry.file_type()?.is_dir() {
+            dirs.push(entry.path());
+        }
+    }
+    dirs.sort_unstable();
+
+    Ok(dirs)
 }
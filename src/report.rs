@@ -0,0 +1,251 @@
+use std::{
+    io::{self, Write},
+    path::PathBuf,
+};
+
+use semver::Version;
+use serde::Serialize;
+
+use crate::diff::FileDiff;
+use crate::git::{CommitRelationship, CommitStatus};
+use crate::package::PackageComparison;
+use crate::policy::{Level, Policy};
+use crate::verdict::{Finding, FindingKind, Verdict};
+
+/// Version of the JSON report schema
+///
+/// Bump this whenever a field is removed, renamed or changes meaning.
+pub const SCHEMA_VERSION: u32 = 2;
+
+#[derive(Debug, Serialize)]
+pub struct Report {
+    pub schema_version: u32,
+    pub denied_findings: usize,
+    pub packages: Vec<PackageReport>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PackageReport {
+    pub name: String,
+    pub version: Version,
+    pub verdict: Verdict,
+    /// The `repository` field of the manifest, as written
+    pub repository: Option<String>,
+    /// The normalized url of the repository, before any `insteadOf` rewrite
+    /// or `--repositories-dir` mirror is applied
+    pub repository_url: Option<String>,
+    pub tag: Option<String>,
+    /// The tag pattern which matched `tag`
+    pub tag_pattern: Option<String>,
+    pub tag_commit: Option<String>,
+    /// The commit located by searching the history, when neither a tag nor
+    /// `vcs_info_sha1` identify it
+    pub heuristic_commit: Option<String>,
+    /// The commit whose files match the registry package, if any was found
+    pub reproducing_commit: Option<String>,
+    pub vcs_info_sha1: Option<String>,
+    /// Whether the package was published from a working tree with uncommitted changes
+    pub vcs_info_dirty: Option<bool>,
+    /// Whether `vcs_info_sha1` is part of the repository
+    pub vcs_info_commit_status: Option<CommitStatus>,
+    /// Where `tag_commit` sits relative to `vcs_info_sha1`, when they differ
+    pub vcs_info_relationship: Option<CommitRelationship>,
+    /// Packaged files which differ between `tag_commit` and `vcs_info_sha1`
+    pub vcs_info_changed_files: Option<Vec<PathBuf>>,
+    /// Files of the package built from `reproducing_commit`, if any, compared
+    /// with the registry package
+    pub comparisons: Vec<PackageComparison>,
+    pub findings: Vec<Finding>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub diffs: Vec<FileDiff>,
+}
+
+impl Report {
+    pub fn new(mut packages: Vec<PackageReport>, policy: &Policy) -> Self {
+        packages.sort_by(|a, b| (&a.name, &a.version).cmp(&(&b.name, &b.version)));
+
+        let mut denied_findings = 0;
+        for finding in packages
+            .iter_mut()
+            .flat_map(|package| &mut package.findings)
+        {
+            finding.level = policy.level(finding.kind);
+            if finding.level == Level::Deny {
+                denied_findings += 1;
+            }
+        }
+
+        Self {
+            schema_version: SCHEMA_VERSION,
+            denied_findings,
+            packages,
+        }
+    }
+
+    pub fn write_json(&self, mut writer: impl Write) -> io::Result<()> {
+        serde_json::to_writer_pretty(&mut writer, self)?;
+        writeln!(writer)
+    }
+
+    pub fn write_human(&self, mut writer: impl Write) -> io::Result<()> {
+        for package in &self.packages {
+            for Finding {
+                kind,
+                level,
+                message,
+            } in &package.findings
+            {
+                let level = match level {
+                    Level::Warn => "warning",
+                    Level::Deny => "error",
+                };
+                writeln!(writer, "{level}[{}] {message}", kind.as_str())?;
+            }
+
+            for FileDiff { diff, .. } in &package.diffs {
+                write!(writer, "{diff}")?;
+            }
+        }
+
+        self.write_summary(&mut writer)?;
+
+        if self.denied_findings > 0 {
+            writeln!(writer)?;
+            writeln!(
+                writer,
+                "error: {} denied finding(s) reported",
+                self.denied_findings
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Write the diffs of every package, or why they couldn't be produced
+    ///
+    /// The findings are written too when the diffs can't explain the verdict.
+    pub fn write_diffs(&self, mut writer: impl Write) -> io::Result<()> {
+        for package in &self.packages {
+            if package.verdict == Verdict::Unverifiable
+                || (package.diffs.is_empty() && package.verdict != Verdict::Reproducible)
+            {
+                for finding in &package.findings {
+                    writeln!(writer, "{}", finding.message)?;
+                }
+            } else if package.diffs.is_empty() {
+                writeln!(
+                    writer,
+                    "Package {} v{} has no differences between the repository and the registry",
+                    package.name, package.version
+                )?;
+            }
+
+            for FileDiff { diff, .. } in &package.diffs {
+                write!(writer, "{diff}")?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn write_summary(&self, writer: &mut impl Write) -> io::Result<()> {
+        let versions = self
+            .packages
+            .iter()
+            .map(|package| package.version.to_string())
+            .collect::<Vec<_>>();
+        let name_width = self
+            .packages
+            .iter()
+            .map(|package| package.name.len())
+            .chain(["Package".len()])
+            .max()
+            .unwrap_or_default();
+        let version_width = versions
+            .iter()
+            .map(String::len)
+            .chain(["Version".len()])
+            .max()
+            .unwrap_or_default();
+
+        writeln!(writer)?;
+        writeln!(
+            writer,
+            "{:name_width$}  {:version_width$}  Verdict",
+            "Package", "Version"
+        )?;
+        for (package, version) in self.packages.iter().zip(&versions) {
+            write!(
+                writer,
+                "{:name_width$}  {:version_width$}  {}",
+                package.name, version, package.verdict
+            )?;
+
+            let mut reasons = package
+                .findings
+                .iter()
+                .filter(|finding| finding.kind.verdict() == package.verdict)
+                .map(|finding| finding.kind.as_str())
+                .collect::<Vec<_>>();
+            reasons.dedup();
+            if reasons.is_empty() {
+                writeln!(writer)?;
+            } else {
+                writeln!(writer, " ({})", reasons.join(", "))?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl PackageReport {
+    pub fn new(lock_info: &cargo_lock::Package) -> Self {
+        Self {
+            name: lock_info.name.as_str().to_owned(),
+            version: lock_info.version.clone(),
+            verdict: Verdict::Reproducible,
+            repository: None,
+            repository_url: None,
+            tag: None,
+            tag_pattern: None,
+            tag_commit: None,
+            heuristic_commit: None,
+            reproducing_commit: None,
+            vcs_info_sha1: None,
+            vcs_info_dirty: None,
+            vcs_info_commit_status: None,
+            vcs_info_relationship: None,
+            vcs_info_changed_files: None,
+            comparisons: Vec::new(),
+            findings: Vec::new(),
+            diffs: Vec::new(),
+        }
+    }
+
+    pub fn finding(&mut self, kind: FindingKind, message: String) {
+        self.verdict = self.verdict.max(kind.verdict());
+        self.findings.push(Finding {
+            kind,
+            level: Level::default(),
+            message,
+        });
+    }
+
+    /// Record an error which aborted the verification of this package
+    ///
+    /// The kind is taken from the [`FindingKind`] context attached to the
+    /// error, falling back to [`FindingKind::Internal`].
+    pub fn error(&mut self, err: &anyhow::Error) {
+        let kind = err
+            .downcast_ref::<FindingKind>()
+            .copied()
+            .unwrap_or(FindingKind::Internal);
+        let mut message = format!("Couldn't verify {} v{}: {err:#}", self.name, self.version);
+        if let Some(repository_url) = &self.repository_url {
+            message.push_str(&format!(" url={repository_url}"));
+        }
+
+        self.finding(kind, message);
+    }
+}
@@ -0,0 +1,270 @@
+use std::fmt::{self, Display};
+
+use serde::Serialize;
+
+use crate::policy::Level;
+
+/// The overall outcome of verifying a package
+///
+/// Variants are ordered by severity, so that the verdict of a package is the
+/// maximum of the verdicts implied by each of its findings.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Verdict {
+    /// The registry package was reproduced from the repository
+    Reproducible,
+    /// The package isn't published, so there is nothing to verify
+    ///
+    /// Only produced by [`FindingKind::WorkspaceMember`], which aborts the
+    /// verification, so it never competes with more severe verdicts.
+    Skipped,
+    /// The registry package was reproduced, but something looked off
+    ReproducibleWithWarnings,
+    /// The package couldn't be checked
+    Unverifiable,
+    /// The registry package differs from what the repository produces
+    Mismatch,
+}
+
+/// A machine-readable reason explaining part of a [`Verdict`]
+///
+/// Errors returned while resolving or analyzing a package carry the kind as
+/// [`anyhow`] context, so that it can be recovered with `downcast_ref`.
+/// [`Display`] gives the human description used in that context, while
+/// [`FindingKind::as_str`] gives the stable identifier.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum FindingKind {
+    /// The package has no source in Cargo.lock, it's a member of the workspace
+    /// or one of its path dependencies
+    WorkspaceMember,
+    /// The package doesn't come from a supported registry
+    UnsupportedSource,
+    /// The `.crate` couldn't be obtained from the registry
+    DownloadFailed,
+    /// Cargo.lock doesn't record a checksum for the package
+    MissingChecksum,
+    /// The `.crate` doesn't match the checksum in Cargo.lock
+    ChecksumMismatch,
+    /// The `.crate` couldn't be read
+    InvalidCrate,
+    /// `Cargo.toml` has no `repository` field
+    NoRepository,
+    /// The package doesn't have a `.cargo_vcs_info.json`
+    MissingVcsInfo,
+    /// The package has a `.cargo_vcs_info.json` which can't be parsed
+    MalformedVcsInfo,
+    /// The package was published from a working tree with uncommitted changes
+    DirtyVcsInfo,
+    /// The `repository` field isn't a usable git url
+    InvalidRepositoryUrl,
+    /// The repository couldn't be cloned
+    CloneFailed,
+    /// The `.crate` or the repository isn't available locally in offline mode
+    UnavailableOffline,
+    /// A previously cloned repository couldn't be updated, so it may lack recent tags
+    FetchFailed,
+    /// No git tag matches the released version
+    MissingTag,
+    /// Several tags match the released version but point at different commits
+    AmbiguousTag,
+    /// The tagged commit differs from the one in `.cargo_vcs_info.json`
+    VcsInfoCommitMismatch,
+    /// The commit in `.cargo_vcs_info.json` isn't in the repository
+    VcsInfoCommitMissing,
+    /// The commit in `.cargo_vcs_info.json` isn't reachable from any branch or tag
+    VcsInfoCommitUnreachable,
+    /// Neither a tag nor `.cargo_vcs_info.json` identify the released commit
+    UnknownCommit,
+    /// The released commit was located by searching the history for the released version
+    HeuristicCommit,
+    /// The released commit couldn't be checked out
+    CheckoutFailed,
+    /// The manifest at `path_in_vcs` doesn't declare the released package
+    PathInVcsMismatch,
+    /// `cargo package` failed on the released commit
+    PackageFailed,
+    /// A file differs between the repository and the registry package
+    FileMismatch,
+    /// The normalized `Cargo.toml` differs semantically between the repository and the registry package
+    ManifestMismatch,
+    /// `Cargo.toml.orig` is formatted differently from the manifest in the repository
+    ManifestOrigFormatting,
+    /// `Cargo.toml.orig` differs semantically from the manifest in the repository
+    ManifestOrigMismatch,
+    /// An unexpected error
+    Internal,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Finding {
+    pub kind: FindingKind,
+    pub level: Level,
+    pub message: String,
+}
+
+impl FindingKind {
+    pub const ALL: [Self; 29] = [
+        Self::WorkspaceMember,
+        Self::UnsupportedSource,
+        Self::DownloadFailed,
+        Self::MissingChecksum,
+        Self::ChecksumMismatch,
+        Self::InvalidCrate,
+        Self::NoRepository,
+        Self::MissingVcsInfo,
+        Self::MalformedVcsInfo,
+        Self::DirtyVcsInfo,
+        Self::InvalidRepositoryUrl,
+        Self::CloneFailed,
+        Self::UnavailableOffline,
+        Self::FetchFailed,
+        Self::MissingTag,
+        Self::AmbiguousTag,
+        Self::VcsInfoCommitMismatch,
+        Self::VcsInfoCommitMissing,
+        Self::VcsInfoCommitUnreachable,
+        Self::UnknownCommit,
+        Self::HeuristicCommit,
+        Self::CheckoutFailed,
+        Self::PathInVcsMismatch,
+        Self::PackageFailed,
+        Self::FileMismatch,
+        Self::ManifestMismatch,
+        Self::ManifestOrigFormatting,
+        Self::ManifestOrigMismatch,
+        Self::Internal,
+    ];
+
+    /// The verdict a package gets when this is its most severe finding
+    pub fn verdict(self) -> Verdict {
+        match self {
+            Self::WorkspaceMember => Verdict::Skipped,
+            Self::MissingChecksum
+            | Self::MissingVcsInfo
+            | Self::DirtyVcsInfo
+            | Self::FetchFailed
+            | Self::MissingTag
+            | Self::AmbiguousTag
+            | Self::VcsInfoCommitMismatch
+            | Self::VcsInfoCommitMissing
+            | Self::VcsInfoCommitUnreachable
+            | Self::HeuristicCommit
+            | Self::ManifestOrigFormatting => Verdict::ReproducibleWithWarnings,
+            Self::UnsupportedSource
+            | Self::DownloadFailed
+            | Self::InvalidCrate
+            | Self::NoRepository
+            | Self::MalformedVcsInfo
+            | Self::InvalidRepositoryUrl
+            | Self::CloneFailed
+            | Self::UnavailableOffline
+            | Self::UnknownCommit
+            | Self::CheckoutFailed
+            | Self::PathInVcsMismatch
+            | Self::PackageFailed
+            | Self::Internal => Verdict::Unverifiable,
+            Self::ChecksumMismatch
+            | Self::FileMismatch
+            | Self::ManifestMismatch
+            | Self::ManifestOrigMismatch => Verdict::Mismatch,
+        }
+    }
+
+    /// Attach this kind to `err`, unless it already carries a more specific one
+    pub fn or_context(self, err: anyhow::Error) -> anyhow::Error {
+        if err.downcast_ref::<Self>().is_some() {
+            err
+        } else {
+            err.context(self)
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::WorkspaceMember => "workspace-member",
+            Self::UnsupportedSource => "unsupported-source",
+            Self::DownloadFailed => "download-failed",
+            Self::MissingChecksum => "missing-checksum",
+            Self::ChecksumMismatch => "checksum-mismatch",
+            Self::InvalidCrate => "invalid-crate",
+            Self::NoRepository => "no-repository",
+            Self::MissingVcsInfo => "missing-vcs-info",
+            Self::MalformedVcsInfo => "malformed-vcs-info",
+            Self::DirtyVcsInfo => "dirty-vcs-info",
+            Self::InvalidRepositoryUrl => "invalid-repository-url",
+            Self::CloneFailed => "clone-failed",
+            Self::UnavailableOffline => "unavailable-offline",
+            Self::FetchFailed => "fetch-failed",
+            Self::MissingTag => "missing-tag",
+            Self::AmbiguousTag => "ambiguous-tag",
+            Self::VcsInfoCommitMismatch => "vcs-info-commit-mismatch",
+            Self::VcsInfoCommitMissing => "vcs-info-commit-missing",
+            Self::VcsInfoCommitUnreachable => "vcs-info-commit-unreachable",
+            Self::UnknownCommit => "unknown-commit",
+            Self::HeuristicCommit => "heuristic-commit",
+            Self::CheckoutFailed => "checkout-failed",
+            Self::PathInVcsMismatch => "path-in-vcs-mismatch",
+            Self::PackageFailed => "package-failed",
+            Self::FileMismatch => "file-mismatch",
+            Self::ManifestMismatch => "manifest-mismatch",
+            Self::ManifestOrigFormatting => "manifest-orig-formatting",
+            Self::ManifestOrigMismatch => "manifest-orig-mismatch",
+            Self::Internal => "internal",
+        }
+    }
+}
+
+impl Verdict {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Reproducible => "reproducible",
+            Self::Skipped => "skipped",
+            Self::ReproducibleWithWarnings => "reproducible-with-warnings",
+            Self::Unverifiable => "unverifiable",
+            Self::Mismatch => "mismatch",
+        }
+    }
+}
+
+impl Display for FindingKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::WorkspaceMember => "not published to a registry",
+            Self::UnsupportedSource => "unsupported package source",
+            Self::DownloadFailed => "couldn't obtain package",
+            Self::MissingChecksum => "missing checksum",
+            Self::ChecksumMismatch => "checksum mismatch",
+            Self::InvalidCrate => "couldn't read package",
+            Self::NoRepository => "no repository",
+            Self::MissingVcsInfo => "missing vcs info",
+            Self::MalformedVcsInfo => "malformed vcs info",
+            Self::DirtyVcsInfo => "published from a dirty working tree",
+            Self::InvalidRepositoryUrl => "repository url isn't valid",
+            Self::CloneFailed => "couldn't clone repository",
+            Self::UnavailableOffline => "unavailable offline",
+            Self::FetchFailed => "couldn't fetch repository",
+            Self::MissingTag => "missing tag",
+            Self::AmbiguousTag => "ambiguous tag",
+            Self::VcsInfoCommitMismatch => "vcs info commit mismatch",
+            Self::VcsInfoCommitMissing => "vcs info commit missing from repository",
+            Self::VcsInfoCommitUnreachable => "vcs info commit unreachable",
+            Self::UnknownCommit => "couldn't determine commit matching registry release",
+            Self::HeuristicCommit => "commit located heuristically",
+            Self::CheckoutFailed => "couldn't checkout commit",
+            Self::PathInVcsMismatch => "package not found at `path_in_vcs`",
+            Self::PackageFailed => "couldn't package",
+            Self::FileMismatch => "file mismatch",
+            Self::ManifestMismatch => "manifest mismatch",
+            Self::ManifestOrigFormatting => "original manifest formatting mismatch",
+            Self::ManifestOrigMismatch => "original manifest mismatch",
+            Self::Internal => "internal error",
+        })
+    }
+}
+
+impl Display for Verdict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}